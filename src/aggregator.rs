@@ -0,0 +1,323 @@
+//! Client-side aggregation of counters, gauges and sets.
+//!
+//! Rather than sending one packet per call, an aggregating `Client` merges
+//! values that share a metric name and tags over a flush window: counters are
+//! summed, gauges keep their last value and sets keep their unique values.
+//!
+//! With extended aggregation, histogram, distribution and timing samples are
+//! buffered too, and sent as multi-value lines like `name:v1:v2:v3|h`
+//! (DogStatsD protocol v1.1).
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Default window over which an aggregating client merges metrics.
+pub const DEFAULT_AGGREGATION_FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The metric name and tags that identify an aggregated value.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Context {
+    pub(crate) metric: String,
+    pub(crate) tags: Option<Vec<String>>,
+}
+
+impl Context {
+    fn new(metric: &str, tags: Option<&Vec<&str>>) -> Self {
+        Self {
+            metric: metric.to_string(),
+            tags: tags.map(|v| v.iter().map(|t| t.to_string()).collect()),
+        }
+    }
+}
+
+/// An aggregated metric, before the client's prefix and tags are applied.
+///
+/// Its values are joined with `:` between the metric name and the suffix,
+/// which holds the metric type and sample rate, e.g. `|h|@0.5`.
+pub(crate) struct Aggregate {
+    pub(crate) metric: String,
+    pub(crate) values: Vec<String>,
+    pub(crate) suffix: String,
+    pub(crate) tags: Option<Vec<String>>,
+}
+
+impl Aggregate {
+    fn single(ctx: Context, value: String, metric_type: &str) -> Self {
+        Self {
+            metric: ctx.metric,
+            values: vec![value],
+            suffix: format!("|{}", metric_type),
+            tags: ctx.tags,
+        }
+    }
+}
+
+/// How an extended aggregator keeps samples.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SampleOptions {
+    /// The most samples kept per context and flush window.
+    pub(crate) max_samples_per_context: Option<usize>,
+    /// Keep a uniform random sample of the values seen, rather than the
+    /// first `max_samples_per_context` of them.
+    pub(crate) reservoir_sampling: bool,
+}
+
+/// The samples kept for a single context.
+#[derive(Default)]
+struct Samples {
+    values: Vec<f64>,
+    seen: usize,
+}
+
+impl Samples {
+    fn add(&mut self, value: f64, options: &SampleOptions) {
+        self.seen += 1;
+        match options.max_samples_per_context {
+            Some(max) if self.values.len() >= max => {
+                if options.reservoir_sampling {
+                    let i = rand::thread_rng().gen_range(0, self.seen);
+                    if i < max {
+                        self.values[i] = value;
+                    }
+                }
+            }
+            _ => self.values.push(value),
+        }
+    }
+
+    fn into_aggregate(self, ctx: Context, metric_type: &str) -> Aggregate {
+        let suffix = if self.seen > self.values.len() {
+            // Let the agent scale up for the samples that were dropped.
+            let rate = self.values.len() as f64 / self.seen as f64;
+            format!("|{}|@{}", metric_type, rate)
+        } else {
+            format!("|{}", metric_type)
+        };
+        Aggregate {
+            metric: ctx.metric,
+            values: self.values.iter().map(|v| v.to_string()).collect(),
+            suffix,
+            tags: ctx.tags,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Aggregator {
+    counts: Mutex<HashMap<Context, f64>>,
+    gauges: Mutex<HashMap<Context, f64>>,
+    sets: Mutex<HashMap<Context, HashSet<String>>>,
+    histograms: Mutex<HashMap<Context, Samples>>,
+    distributions: Mutex<HashMap<Context, Samples>>,
+    timings: Mutex<HashMap<Context, Samples>>,
+    // Samples are only aggregated when this is set.
+    extended: Option<SampleOptions>,
+}
+
+impl Aggregator {
+    /// An aggregator that also buffers histogram, distribution and timing samples.
+    pub(crate) fn extended(options: SampleOptions) -> Self {
+        Self {
+            extended: Some(options),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn is_extended(&self) -> bool {
+        self.extended.is_some()
+    }
+
+    pub(crate) fn count(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        *counts.entry(Context::new(metric, tags)).or_insert(0.0) += value;
+    }
+
+    pub(crate) fn gauge(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let mut gauges = self.gauges.lock().unwrap_or_else(|e| e.into_inner());
+        gauges.insert(Context::new(metric, tags), value);
+    }
+
+    pub(crate) fn set(&self, metric: &str, value: String, tags: Option<&Vec<&str>>) {
+        let mut sets = self.sets.lock().unwrap_or_else(|e| e.into_inner());
+        sets.entry(Context::new(metric, tags))
+            .or_default()
+            .insert(value);
+    }
+
+    pub(crate) fn histogram(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.sample(&self.histograms, metric, value, tags);
+    }
+
+    pub(crate) fn distribution(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.sample(&self.distributions, metric, value, tags);
+    }
+
+    pub(crate) fn timing(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.sample(&self.timings, metric, value, tags);
+    }
+
+    fn sample(
+        &self,
+        samples: &Mutex<HashMap<Context, Samples>>,
+        metric: &str,
+        value: f64,
+        tags: Option<&Vec<&str>>,
+    ) {
+        let options = match &self.extended {
+            Some(options) => options,
+            None => return,
+        };
+        let mut samples = samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples
+            .entry(Context::new(metric, tags))
+            .or_default()
+            .add(value, options);
+    }
+
+    /// Take every value aggregated since the last flush.
+    pub(crate) fn flush(&self) -> Vec<Aggregate> {
+        let counts = take(&self.counts);
+        let gauges = take(&self.gauges);
+        let sets = take(&self.sets);
+
+        let mut aggregates = vec![];
+        for (ctx, value) in counts {
+            aggregates.push(Aggregate::single(ctx, value.to_string(), "c"));
+        }
+        for (ctx, value) in gauges {
+            aggregates.push(Aggregate::single(ctx, value.to_string(), "g"));
+        }
+        for (ctx, values) in sets {
+            for value in values {
+                aggregates.push(Aggregate::single(ctx.clone(), value, "s"));
+            }
+        }
+        for (samples, metric_type) in &[
+            (&self.histograms, "h"),
+            (&self.distributions, "d"),
+            (&self.timings, "ms"),
+        ] {
+            for (ctx, samples) in take(samples) {
+                aggregates.push(samples.into_aggregate(ctx, metric_type));
+            }
+        }
+        aggregates
+    }
+}
+
+fn take<T: Default>(value: &Mutex<T>) -> T {
+    std::mem::take(&mut *value.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flush_sorted(aggregator: &Aggregator) -> Vec<(String, Option<Vec<String>>)> {
+        let mut lines: Vec<_> = aggregator
+            .flush()
+            .into_iter()
+            .map(|a| {
+                let data = format!("{}:{}{}", a.metric, a.values.join(":"), a.suffix);
+                (data, a.tags)
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_aggregates_counts_gauges_and_sets() {
+        let aggregator = Aggregator::default();
+        let tags = vec!["tag1"];
+
+        aggregator.count("hits", 1.0, None);
+        aggregator.count("hits", 2.5, None);
+        aggregator.count("hits", 1.0, Some(&tags));
+        aggregator.gauge("load", 1.0, None);
+        aggregator.gauge("load", 3.0, None);
+        aggregator.set("users", "a".into(), None);
+        aggregator.set("users", "b".into(), None);
+        aggregator.set("users", "a".into(), None);
+
+        assert_eq!(
+            flush_sorted(&aggregator),
+            vec![
+                ("hits:1|c".to_string(), Some(vec!["tag1".to_string()])),
+                ("hits:3.5|c".to_string(), None),
+                ("load:3|g".to_string(), None),
+                ("users:a|s".to_string(), None),
+                ("users:b|s".to_string(), None),
+            ]
+        );
+        assert!(aggregator.flush().is_empty());
+    }
+
+    #[test]
+    fn test_samples_only_aggregated_when_extended() {
+        let aggregator = Aggregator::default();
+        assert!(!aggregator.is_extended());
+        aggregator.histogram("size", 1.0, None);
+        assert!(aggregator.flush().is_empty());
+    }
+
+    #[test]
+    fn test_aggregates_samples() {
+        let aggregator = Aggregator::extended(SampleOptions::default());
+
+        aggregator.histogram("size", 1.0, None);
+        aggregator.histogram("size", 2.5, None);
+        aggregator.distribution("latency", 3.0, None);
+        aggregator.timing("latency", 4.0, None);
+        aggregator.timing("latency", 5.0, None);
+
+        assert_eq!(
+            flush_sorted(&aggregator),
+            vec![
+                ("latency:3|d".to_string(), None),
+                ("latency:4:5|ms".to_string(), None),
+                ("size:1:2.5|h".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_caps_samples_per_context() {
+        let aggregator = Aggregator::extended(SampleOptions {
+            max_samples_per_context: Some(2),
+            reservoir_sampling: false,
+        });
+
+        for i in 0..4 {
+            aggregator.histogram("size", i as f64, None);
+        }
+
+        assert_eq!(
+            flush_sorted(&aggregator),
+            vec![("size:0:1|h|@0.5".to_string(), None)]
+        );
+    }
+
+    #[test]
+    fn test_reservoir_samples_per_context() {
+        let aggregator = Aggregator::extended(SampleOptions {
+            max_samples_per_context: Some(10),
+            reservoir_sampling: true,
+        });
+
+        for i in 0..1000 {
+            aggregator.histogram("size", i as f64, None);
+        }
+
+        let aggregates = aggregator.flush();
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].values.len(), 10);
+        assert_eq!(aggregates[0].suffix, "|h|@0.01");
+        // The reservoir should hold more than just the first values seen.
+        assert!(aggregates[0]
+            .values
+            .iter()
+            .any(|v| v.parse::<f64>().unwrap() >= 10.0));
+    }
+}
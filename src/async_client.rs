@@ -0,0 +1,572 @@
+//! A tokio-based client for async services.
+//!
+//! Enabled with the `tokio` feature. An `AsyncClient` formats metrics exactly
+//! like a `Client`, but does no I/O on the calling task: each datagram is
+//! queued to a background task that packs them into as few packets as
+//! possible and writes them with a `tokio::net::UdpSocket` or
+//! `tokio::net::UnixDatagram`. Aggregates and telemetry are flushed by a
+//! background task too.
+//!
+//! There are two ways to queue metrics:
+//!
+//! * The `Client` methods, reached through `Deref`, never wait. When the
+//!   queue is full the datagram is dropped, reported to the error handler
+//!   and counted by telemetry, like a failed UDP send. The queue holds
+//!   `buffer_queue_size` datagrams, 4096 by default.
+//! * `send_pipeline` and `flush` wait for room in the queue, so nothing is
+//!   dropped and a caller that outpaces the socket is slowed down instead.
+//!
+//! ```ignore
+//! use datadog_statsd::{AsyncClient, ClientConfig};
+//!
+//! let config = ClientConfig::builder(("127.0.0.1", 8125)).prefix("myapp").build();
+//! let client = AsyncClient::new(&config).await?;
+//!
+//! client.incr("requests", Some(&vec!["route:home"]));
+//!
+//! let mut pipeline = client.pipeline();
+//! pipeline.gauge("queue.depth", 12.0, None);
+//! client.send_pipeline(&mut pipeline).await;
+//!
+//! client.flush().await;
+//! ```
+use crate::buffer::{DEFAULT_BUFFER_FLUSH_INTERVAL, DEFAULT_BUFFER_QUEUE_SIZE};
+use crate::client::{
+    Client, ClientConfig, ErrorHandler, FlushSchedule, InternalClient, Pipeline, StatsdAddr,
+    StatsdError, ToStatsdAddr,
+};
+use crate::packet::PacketBuilder;
+use crate::telemetry::Telemetry;
+use crate::transport::Transport;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Deref;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender, WeakSender};
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+
+enum Message {
+    Data(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// A socket written to by the sender task.
+enum AsyncSocket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    UnixDatagram(UnixDatagram, PathBuf),
+}
+
+impl AsyncSocket {
+    async fn connect(addr: StatsdAddr) -> io::Result<Self> {
+        match addr {
+            StatsdAddr::Udp(addr) => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Ok(AsyncSocket::Udp(socket))
+            }
+            #[cfg(unix)]
+            StatsdAddr::UnixDatagram(path) => {
+                Ok(AsyncSocket::UnixDatagram(UnixDatagram::unbound()?, path))
+            }
+            #[cfg(not(unix))]
+            StatsdAddr::UnixDatagram(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix domain sockets are not supported on this platform",
+            )),
+            StatsdAddr::UnixStream(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix stream sockets are not supported by AsyncClient",
+            )),
+        }
+    }
+
+    async fn send(&self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            AsyncSocket::Udp(socket) => socket.send(data).await,
+            #[cfg(unix)]
+            AsyncSocket::UnixDatagram(socket, path) => socket.send_to(data, path).await,
+        }
+    }
+}
+
+/// The transport handed to the inner `Client`, which queues datagrams for
+/// the sender task without blocking.
+struct QueueTransport {
+    sender: Sender<Message>,
+    max_packet_size: usize,
+}
+
+impl Transport for QueueTransport {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        match self.sender.try_send(Message::Data(data.to_vec())) {
+            Ok(()) => Ok(data.len()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "sender queue is full",
+            )),
+            Err(TrySendError::Closed(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "sender task has stopped",
+            )),
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+}
+
+/// A statsd client for tokio runtimes.
+///
+/// Derefs to `Client`, so it has the same methods for sending metrics,
+/// events and service checks. Those methods only queue datagrams without
+/// waiting, so they are safe to call from async code. Use `send_pipeline`
+/// to wait for room in the queue instead, and the async `flush` to wait
+/// until everything queued has been written.
+///
+/// Must be constructed inside a tokio runtime, which runs its background
+/// tasks. Whatever is pending is written when the last clone is dropped,
+/// as long as the runtime is still running.
+#[derive(Clone)]
+pub struct AsyncClient {
+    client: Client,
+    sender: Sender<Message>,
+}
+
+impl AsyncClient {
+    /// Construct a new async statsd client given a client config.
+    ///
+    /// Only UDP and Unix datagram addresses are supported.
+    pub async fn new<T: ToStatsdAddr>(
+        client_config: &ClientConfig<T>,
+    ) -> Result<AsyncClient, StatsdError> {
+        let addr = client_config.to_statsd_addr()?;
+        let transport_name = match addr {
+            StatsdAddr::UnixDatagram(_) => "uds",
+            _ => "udp",
+        };
+        let max_packet_size = client_config
+            .max_packet_size
+            .unwrap_or_else(|| addr.default_packet_size());
+        let socket = AsyncSocket::connect(addr).await?;
+
+        // A tokio channel can't be unbuffered, so it holds at least one datagram.
+        let queue_size = client_config
+            .buffer_queue_size
+            .unwrap_or(DEFAULT_BUFFER_QUEUE_SIZE)
+            .max(1);
+        let (sender, receiver) = mpsc::channel(queue_size);
+        let transport = QueueTransport {
+            sender: sender.clone(),
+            max_packet_size,
+        };
+        let telemetry = Telemetry::from_config(client_config);
+        let client = Client::unscheduled(
+            Box::new(transport),
+            transport_name,
+            client_config,
+            telemetry.clone(),
+        );
+        let worker = Worker {
+            socket,
+            error_handler: client_config.error_handler.clone(),
+            telemetry,
+        };
+        let flush_interval = client_config
+            .buffer_flush_interval
+            .unwrap_or(DEFAULT_BUFFER_FLUSH_INTERVAL);
+        tokio::spawn(worker.run(receiver, max_packet_size, flush_interval));
+
+        let schedule = FlushSchedule::new(client_config);
+        if schedule.aggregation.is_some() || schedule.telemetry.is_some() {
+            tokio::spawn(run_flusher(
+                Arc::downgrade(&client.client),
+                sender.downgrade(),
+                schedule,
+            ));
+        }
+        Ok(AsyncClient { client, sender })
+    }
+
+    /// The underlying client.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Queue everything in a pipeline for the sender task, waiting for room
+    /// in the queue rather than dropping datagrams when it's full.
+    ///
+    /// The lines are packed by the sender task, so the pipeline's max UDP
+    /// size isn't used.
+    pub async fn send_pipeline(&self, pipeline: &mut Pipeline) {
+        let lines = pipeline.take_lines(&self.client.client);
+        queue(&self.client.client, &self.sender, lines).await;
+    }
+
+    /// Flush anything aggregated or queued by the client, waiting until it
+    /// has been written to the socket.
+    ///
+    /// Aggregates wait for room in the queue, like `send_pipeline`.
+    pub async fn flush(&self) {
+        let lines = self.client.client.aggregate_lines();
+        queue(&self.client.client, &self.sender, lines).await;
+        let (ack, done) = oneshot::channel();
+        if self.sender.send(Message::Flush(ack)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+}
+
+/// The `Client` methods queue datagrams without waiting. When the queue
+/// already holds `buffer_queue_size` datagrams, new ones are dropped,
+/// reported to the error handler and counted by telemetry.
+impl Deref for AsyncClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+struct Worker {
+    socket: AsyncSocket,
+    error_handler: Option<ErrorHandler>,
+    telemetry: Option<Arc<Telemetry>>,
+}
+
+impl Worker {
+    async fn run(
+        self,
+        mut receiver: Receiver<Message>,
+        max_packet_size: usize,
+        flush_interval: Duration,
+    ) {
+        let mut builder = PacketBuilder::new(max_packet_size);
+        let mut next_flush = Instant::now() + flush_interval;
+        loop {
+            match time::timeout_at(next_flush, receiver.recv()).await {
+                Ok(Some(Message::Data(data))) => match builder.push(&data) {
+                    Ok(Some(packet)) => self.send(&packet).await,
+                    Ok(None) => {}
+                    Err(e) => self.discard(data.len(), e),
+                },
+                Ok(Some(Message::Flush(ack))) => {
+                    if let Some(packet) = builder.finish() {
+                        self.send(&packet).await;
+                    }
+                    let _ = ack.send(());
+                }
+                Err(_) => {
+                    if let Some(packet) = builder.finish() {
+                        self.send(&packet).await;
+                    }
+                    next_flush = Instant::now() + flush_interval;
+                }
+                // Every sender has been dropped.
+                Ok(None) => {
+                    if let Some(packet) = builder.finish() {
+                        self.send(&packet).await;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, packet: &[u8]) {
+        match self.socket.send(packet).await {
+            Ok(_) => {
+                if let Some(telemetry) = &self.telemetry {
+                    telemetry.packet_sent(packet.len());
+                }
+            }
+            Err(e) => self.discard(packet.len(), e.into()),
+        }
+    }
+
+    /// Count a packet that wasn't sent and report why.
+    fn discard(&self, size: usize, error: StatsdError) {
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.packet_dropped(size);
+        }
+        self.report(error);
+    }
+
+    fn report(&self, error: StatsdError) {
+        if let Some(error_handler) = &self.error_handler {
+            error_handler.handle(&error);
+        }
+    }
+}
+
+/// Queue lines for the sender task, waiting for room rather than dropping
+/// them when the queue is full.
+async fn queue(client: &InternalClient, sender: &Sender<Message>, lines: Vec<String>) {
+    for line in lines {
+        let len = line.len();
+        if sender.send(Message::Data(line.into_bytes())).await.is_err() {
+            client.record(|t| t.packet_dropped(len));
+            client.report(StatsdError::IoError(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "sender task has stopped",
+            )));
+        }
+    }
+}
+
+/// Flush aggregates and telemetry on their schedules until the client is
+/// dropped, waiting for room in the queue.
+///
+/// Only weak handles are kept, so the sender task still sees the channel
+/// close once every `AsyncClient` is dropped.
+async fn run_flusher(
+    client: Weak<InternalClient>,
+    sender: WeakSender<Message>,
+    schedule: FlushSchedule,
+) {
+    let now = Instant::now();
+    let mut next_aggregation = schedule.aggregation.map(|i| now + i);
+    let mut next_telemetry = schedule.telemetry.map(|i| now + i);
+    loop {
+        let next = match (next_aggregation, next_telemetry) {
+            (Some(a), Some(t)) => a.min(t),
+            (Some(next), None) | (None, Some(next)) => next,
+            (None, None) => return,
+        };
+        time::sleep_until(next).await;
+        let (client, sender) = match (client.upgrade(), sender.upgrade()) {
+            (Some(client), Some(sender)) => (client, sender),
+            _ => return,
+        };
+        let now = Instant::now();
+        if let (Some(next), Some(interval)) = (next_aggregation, schedule.aggregation) {
+            if next <= now {
+                queue(&client, &sender, client.aggregate_lines()).await;
+                next_aggregation = Some(now + interval);
+            }
+        }
+        if let (Some(next), Some(interval)) = (next_telemetry, schedule.telemetry) {
+            if next <= now {
+                queue(&client, &sender, client.telemetry_lines()).await;
+                next_telemetry = Some(now + interval);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn make_server() -> (UdpSocket, SocketAddr) {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        (server, addr)
+    }
+
+    async fn server_recv(server: &UdpSocket) -> String {
+        let mut buf = [0; 512];
+        let len = time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+            .await
+            .expect("timed out waiting for a packet")
+            .unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_async_client_packs_and_flushes() {
+        let (server, addr) = make_server().await;
+        let config = ClientConfig::builder(addr)
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common"])
+            .buffer_flush_interval(Duration::from_secs(60))
+            .telemetry(false)
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        client.incr("metric", None);
+        client.gauge("metric", 9.1, Some(&vec!["tag1"]));
+        client.flush().await;
+
+        assert_eq!(
+            server_recv(&server).await,
+            "myapp.metric:1|c|#tag1common\nmyapp.metric:9.1|g|#tag1common,tag1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_client_flushes_on_interval() {
+        let (server, addr) = make_server().await;
+        let config = ClientConfig::builder(addr)
+            .buffer_flush_interval(Duration::from_millis(10))
+            .telemetry(false)
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        client.set("users", "user-123", None);
+
+        assert_eq!(server_recv(&server).await, "users:user-123|s");
+    }
+
+    #[tokio::test]
+    async fn test_async_client_flushes_aggregates() {
+        let (server, addr) = make_server().await;
+        let config = ClientConfig::builder(addr)
+            .aggregation(true)
+            .aggregation_flush_interval(Duration::from_secs(3600))
+            .telemetry(false)
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        client.count("hits", 1.0, None);
+        client.count("hits", 2.0, None);
+        client.flush().await;
+
+        assert_eq!(server_recv(&server).await, "hits:3|c");
+    }
+
+    #[tokio::test]
+    async fn test_async_client_sends_pipelines() {
+        let (server, addr) = make_server().await;
+        let config = ClientConfig::builder(addr).telemetry(false).build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.count("metric", 12.2, None);
+        pipeline.send(&client);
+        client.flush().await;
+
+        assert_eq!(server_recv(&server).await, "metric:9.1|g\nmetric:12.2|c");
+    }
+
+    #[tokio::test]
+    async fn test_async_client_sends_pipelines_waiting_for_the_queue() {
+        let (server, addr) = make_server().await;
+        let config = ClientConfig::builder(addr)
+            .max_packet_size(8192)
+            .buffer_flush_interval(Duration::from_millis(10))
+            .buffer_queue_size(16)
+            .telemetry(false)
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        // More lines than the queue holds at once, so sending has to wait
+        // for the sender task.
+        let mut pipeline = client.pipeline();
+        for i in 0..64 {
+            pipeline.gauge("metric", i as f64, None);
+        }
+        let sent = client.send_pipeline(&mut pipeline);
+        let received = async {
+            let mut buf = vec![0; 8192];
+            let mut lines = 0;
+            while lines < 64 {
+                let len = time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+                    .await
+                    .expect("timed out waiting for a packet")
+                    .unwrap();
+                lines += buf[..len].split(|b| *b == b'\n').count();
+            }
+            lines
+        };
+        let ((), lines) = tokio::join!(sent, received);
+        assert_eq!(lines, 64);
+    }
+
+    #[tokio::test]
+    async fn test_async_client_queues_without_writing() {
+        let (server, addr) = make_server().await;
+        let config = ClientConfig::builder(addr)
+            .buffer_flush_interval(Duration::from_secs(60))
+            .telemetry(false)
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        // The tests run on a single threaded runtime, so nothing is written
+        // until this task yields to the sender task.
+        client.incr("metric", None);
+        let mut buf = [0; 512];
+        assert_eq!(
+            server.try_recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        client.flush().await;
+        assert_eq!(server_recv(&server).await, "metric:1|c");
+    }
+
+    #[tokio::test]
+    async fn test_async_client_drops_when_the_queue_is_full() {
+        use std::sync::Mutex;
+
+        let (server, addr) = make_server().await;
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let config = ClientConfig::builder(addr)
+            .buffer_flush_interval(Duration::from_secs(60))
+            .buffer_queue_size(2)
+            .telemetry(false)
+            .error_handler(move |e| errors_clone.lock().unwrap().push(e.to_string()))
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        // Nothing drains the queue until this task yields.
+        client.incr("metric", None);
+        client.incr("metric", None);
+        client.incr("metric", None);
+        client.flush().await;
+
+        assert_eq!(server_recv(&server).await, "metric:1|c\nmetric:1|c");
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec!["io error: sender queue is full"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_client_flush_waits_to_queue_aggregates() {
+        use std::sync::Mutex;
+
+        let (server, addr) = make_server().await;
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let config = ClientConfig::builder(addr)
+            .aggregation(true)
+            .aggregation_flush_interval(Duration::from_secs(3600))
+            .buffer_flush_interval(Duration::from_secs(60))
+            .buffer_queue_size(1)
+            .telemetry(false)
+            .error_handler(move |e| errors_clone.lock().unwrap().push(e.to_string()))
+            .build();
+        let client = AsyncClient::new(&config).await.unwrap();
+
+        // Fill the queue, so the aggregates have to wait for room.
+        client.event("Title", "Text", crate::client::AlertType::Info, None);
+        client.count("hits", 3.0, None);
+        client.flush().await;
+
+        assert_eq!(server_recv(&server).await, "_e{5,4}:Title|Text\nhits:3|c");
+        assert!(errors.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_async_client_rejects_unix_streams() {
+        let addr: StatsdAddr = "unixstream:///tmp/dsd.socket".parse().unwrap();
+        let config = ClientConfig::builder(addr).build();
+        assert!(AsyncClient::new(&config).await.is_err());
+    }
+}
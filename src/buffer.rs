@@ -0,0 +1,338 @@
+//! Buffered sending of metrics.
+//!
+//! `BufferedTransport` queues every datagram to a background worker that
+//! packs them into as few packets as possible.
+use crate::client::{ErrorHandler, StatsdError};
+use crate::packet::PacketBuilder;
+use crate::telemetry::Telemetry;
+use crate::transport::Transport;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Default interval at which a `BufferedTransport` flushes a partial packet.
+pub const DEFAULT_BUFFER_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default number of datagrams that may be queued for the worker before new
+/// ones are dropped.
+pub const DEFAULT_BUFFER_QUEUE_SIZE: usize = 4096;
+
+enum Message {
+    Data(Vec<u8>),
+    Flush(SyncSender<()>),
+}
+
+/// A transport that hands datagrams to a background worker thread.
+///
+/// The worker packs them into packets of up to `max_packet_size` bytes and
+/// writes a packet to the wrapped transport once it is full or once
+/// `flush_interval` has passed. Whatever is pending is written when the
+/// transport is flushed or dropped.
+pub struct BufferedTransport {
+    sender: Option<SyncSender<Message>>,
+    worker: Option<JoinHandle<()>>,
+    max_packet_size: usize,
+}
+
+impl BufferedTransport {
+    pub fn new<T>(transport: T, max_packet_size: usize, flush_interval: Duration) -> Self
+    where
+        T: Transport + 'static,
+    {
+        Self::spawn(
+            transport,
+            max_packet_size,
+            flush_interval,
+            DEFAULT_BUFFER_QUEUE_SIZE,
+            None,
+            None,
+        )
+    }
+
+    /// Like `new`, but errors from writing to the wrapped transport on the
+    /// worker thread are passed to `error_handler`.
+    pub fn with_error_handler<T>(
+        transport: T,
+        max_packet_size: usize,
+        flush_interval: Duration,
+        error_handler: ErrorHandler,
+    ) -> Self
+    where
+        T: Transport + 'static,
+    {
+        Self::spawn(
+            transport,
+            max_packet_size,
+            flush_interval,
+            DEFAULT_BUFFER_QUEUE_SIZE,
+            Some(error_handler),
+            None,
+        )
+    }
+
+    /// Spawn the worker, which counts the packets it writes and fails to
+    /// write with `telemetry`. Up to `queue_size` datagrams are queued for it.
+    pub(crate) fn spawn<T>(
+        transport: T,
+        max_packet_size: usize,
+        flush_interval: Duration,
+        queue_size: usize,
+        error_handler: Option<ErrorHandler>,
+        telemetry: Option<Arc<Telemetry>>,
+    ) -> Self
+    where
+        T: Transport + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let worker = Worker {
+            transport,
+            error_handler,
+            telemetry,
+        };
+        let worker = thread::Builder::new()
+            .name("dogstatsd-sender".into())
+            .spawn(move || worker.run(receiver, max_packet_size, flush_interval))
+            .expect("failed to spawn dogstatsd sender thread");
+        Self {
+            sender: Some(sender),
+            worker: Some(worker),
+            max_packet_size,
+        }
+    }
+
+    fn sender(&self) -> io::Result<&SyncSender<Message>> {
+        self.sender
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "sender is shut down"))
+    }
+}
+
+impl Transport for BufferedTransport {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        match self.sender()?.try_send(Message::Data(data.to_vec())) {
+            Ok(()) => Ok(data.len()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "sender queue is full",
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "sender thread has stopped",
+            )),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let (ack, done) = mpsc::sync_channel(1);
+        self.sender()?
+            .send(Message::Flush(ack))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "sender thread has stopped"))?;
+        done.recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "sender thread has stopped"))
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+}
+
+impl Drop for BufferedTransport {
+    fn drop(&mut self) {
+        // Disconnecting the channel tells the worker to write what's pending and exit.
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Worker<T> {
+    transport: T,
+    error_handler: Option<ErrorHandler>,
+    telemetry: Option<Arc<Telemetry>>,
+}
+
+impl<T: Transport> Worker<T> {
+    fn run(self, receiver: Receiver<Message>, max_packet_size: usize, flush_interval: Duration) {
+        let mut builder = PacketBuilder::new(max_packet_size);
+        let mut next_flush = Instant::now() + flush_interval;
+        loop {
+            let timeout = next_flush.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Message::Data(data)) => match builder.push(&data) {
+                    Ok(Some(packet)) => self.send(&packet),
+                    Ok(None) => {}
+                    Err(e) => self.discard(data.len(), e),
+                },
+                Ok(Message::Flush(ack)) => {
+                    self.flush(&mut builder);
+                    let _ = ack.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(packet) = builder.finish() {
+                        self.send(&packet);
+                    }
+                    next_flush = Instant::now() + flush_interval;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush(&mut builder);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send(&self, packet: &[u8]) {
+        match self.transport.send(packet) {
+            Ok(_) => {
+                if let Some(telemetry) = &self.telemetry {
+                    telemetry.packet_sent(packet.len());
+                }
+            }
+            Err(e) => self.discard(packet.len(), e.into()),
+        }
+    }
+
+    /// Count a packet that wasn't sent and report why.
+    fn discard(&self, size: usize, error: StatsdError) {
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.packet_dropped(size);
+        }
+        self.report(error);
+    }
+
+    fn flush(&self, builder: &mut PacketBuilder) {
+        if let Some(packet) = builder.finish() {
+            self.send(&packet);
+        }
+        if let Err(e) = self.transport.flush() {
+            self.report(e.into());
+        }
+    }
+
+    fn report(&self, error: StatsdError) {
+        if let Some(error_handler) = &self.error_handler {
+            error_handler.handle(&error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::CapturingTransport;
+
+    #[test]
+    fn test_buffered_transport_flushes_on_size() {
+        let capture = CapturingTransport::new();
+        let transport = BufferedTransport::new(capture.clone(), 21, Duration::from_secs(60));
+
+        transport.send(b"metric:1|c").unwrap();
+        transport.send(b"metric:2|c").unwrap();
+        transport.send(b"metric:3|c").unwrap();
+        transport.flush().unwrap();
+
+        assert_eq!(
+            capture.datagrams(),
+            vec!["metric:1|c\nmetric:2|c", "metric:3|c"]
+        );
+    }
+
+    #[test]
+    fn test_buffered_transport_flushes_on_interval() {
+        let capture = CapturingTransport::new();
+        let transport = BufferedTransport::new(capture.clone(), 512, Duration::from_millis(10));
+
+        transport.send(b"metric:1|c").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while capture.datagrams().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(capture.datagrams(), vec!["metric:1|c"]);
+    }
+
+    #[test]
+    fn test_buffered_transport_flushes_on_drop() {
+        let capture = CapturingTransport::new();
+        let transport = BufferedTransport::new(capture.clone(), 512, Duration::from_secs(60));
+
+        transport.send(b"metric:1|c").unwrap();
+        transport.send(b"metric:2|g").unwrap();
+        drop(transport);
+
+        assert_eq!(capture.datagrams(), vec!["metric:1|c\nmetric:2|g"]);
+    }
+
+    #[test]
+    fn test_buffered_transport_reports_errors() {
+        use std::sync::Mutex;
+
+        struct FailingTransport;
+
+        impl Transport for FailingTransport {
+            fn send(&self, _data: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+            }
+        }
+
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let telemetry = Arc::new(Telemetry::default());
+        let transport = BufferedTransport::spawn(
+            FailingTransport,
+            512,
+            Duration::from_secs(60),
+            DEFAULT_BUFFER_QUEUE_SIZE,
+            Some(ErrorHandler::new(move |e| {
+                errors_clone.lock().unwrap().push(e.to_string())
+            })),
+            Some(Arc::clone(&telemetry)),
+        );
+
+        transport.send(b"metric:1|c").unwrap();
+        transport.flush().unwrap();
+
+        assert_eq!(*errors.lock().unwrap(), vec!["io error: refused"]);
+        let lines = telemetry.flush();
+        assert!(lines
+            .iter()
+            .any(|(l, _)| l == "datadog.dogstatsd.client.packets_dropped:1|c"));
+    }
+
+    #[test]
+    fn test_buffered_transport_drops_oversized_lines() {
+        use std::sync::Mutex;
+
+        let capture = CapturingTransport::new();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let telemetry = Arc::new(Telemetry::default());
+        let transport = BufferedTransport::spawn(
+            capture.clone(),
+            10,
+            Duration::from_secs(60),
+            DEFAULT_BUFFER_QUEUE_SIZE,
+            Some(ErrorHandler::new(move |e| {
+                errors_clone.lock().unwrap().push(e.to_string())
+            })),
+            Some(Arc::clone(&telemetry)),
+        );
+
+        transport.send(b"metric:1|c").unwrap();
+        transport.send(b"metric:100|c").unwrap();
+        transport.flush().unwrap();
+
+        assert_eq!(capture.datagrams(), vec!["metric:1|c"]);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec!["payload of 12 bytes exceeds the max packet size of 10 bytes"]
+        );
+        let lines = telemetry.flush();
+        assert!(lines
+            .iter()
+            .any(|(l, _)| l == "datadog.dogstatsd.client.bytes_dropped:12|c"));
+    }
+}
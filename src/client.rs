@@ -1,10 +1,22 @@
+use crate::aggregator::{Aggregate, Aggregator, SampleOptions, DEFAULT_AGGREGATION_FLUSH_INTERVAL};
+use crate::buffer::{BufferedTransport, DEFAULT_BUFFER_FLUSH_INTERVAL, DEFAULT_BUFFER_QUEUE_SIZE};
+use crate::origin;
+use crate::packet::PacketBuilder;
+use crate::telemetry::{MetricType, Telemetry, DEFAULT_TELEMETRY_INTERVAL};
+use crate::timer::TimerGuard;
+use crate::transport::{self, Transport};
+pub use crate::transport::{DEFAULT_UDP_PACKET_SIZE, DEFAULT_UDS_PACKET_SIZE};
 use futures::Future;
 use rand;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::fmt;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::sync::Arc;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Weak};
+use std::thread;
 use std::time;
 use thiserror::Error;
 
@@ -14,9 +26,205 @@ pub enum StatsdError {
     IoError(#[from] std::io::Error),
     #[error("{0}")]
     AddrParseError(String),
+    #[error("payload of {size} bytes exceeds the max packet size of {max} bytes")]
+    PacketTooLarge { size: usize, max: usize },
+    #[error("invalid metric name: {0:?}")]
+    InvalidMetricName(String),
 }
 
-/// A config to build a statsd Client.  The address field should implement `std::net::ToSocketAddrs`.
+/// A callback that is given every error a `Client` would otherwise discard.
+///
+/// Metrics are fire-and-forget, so failed sends, oversized payloads and
+/// invalid metric names are reported here rather than returned. For buffered
+/// clients the handler is called from the background sender thread.
+///
+/// ```ignore
+/// use datadog_statsd::ClientConfig;
+///
+/// let config = ClientConfig::builder(("127.0.0.1", 8125))
+///     .error_handler(|e| eprintln!("dropped metric: {}", e))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct ErrorHandler(Arc<dyn Fn(&StatsdError) + Send + Sync>);
+
+impl ErrorHandler {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&StatsdError) + Send + Sync + 'static,
+    {
+        ErrorHandler(Arc::new(f))
+    }
+
+    pub fn handle(&self, error: &StatsdError) {
+        (self.0)(error)
+    }
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
+
+/// Check that a metric name can't be mistaken for other parts of a datagram.
+fn validate_metric_name(metric: &str) -> Result<(), StatsdError> {
+    if metric.is_empty() || metric.contains([':', '|', '@', '#', '\n']) {
+        Err(StatsdError::InvalidMetricName(metric.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Replace the characters that would end a tag or the tag list, for tags
+/// built from values the caller doesn't control.
+#[cfg(any(feature = "metrics", feature = "tracing"))]
+pub(crate) fn sanitize_tag(value: &str) -> String {
+    value.replace(['|', ',', '\n', '\r'], "_")
+}
+
+/// Seconds since the Unix epoch, as sent in a metric's `|T` field.
+/// Times before the epoch are sent as 0.
+fn unix_timestamp(timestamp: time::SystemTime) -> u64 {
+    timestamp
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The resolved address of a DogStatsD server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatsdAddr {
+    /// A UDP socket address.
+    Udp(SocketAddr),
+    /// A unix domain socket in datagram mode, e.g. `unix:///var/run/datadog/dsd.socket`.
+    UnixDatagram(PathBuf),
+    /// A unix domain socket in stream mode, e.g. `unixstream:///var/run/datadog/dsd.socket`.
+    UnixStream(PathBuf),
+}
+
+impl StatsdAddr {
+    /// The default max packet size for this kind of address.
+    pub fn default_packet_size(&self) -> usize {
+        match self {
+            StatsdAddr::Udp(_) => DEFAULT_UDP_PACKET_SIZE,
+            StatsdAddr::UnixDatagram(_) | StatsdAddr::UnixStream(_) => DEFAULT_UDS_PACKET_SIZE,
+        }
+    }
+}
+
+/// Parses an address that may carry a scheme: `udp://host:port`, `unix:///path`,
+/// `unixgram:///path` or `unixstream:///path`. Addresses without a scheme are
+/// resolved like `std::net::ToSocketAddrs`.
+impl FromStr for StatsdAddr {
+    type Err = StatsdError;
+
+    fn from_str(address: &str) -> Result<Self, StatsdError> {
+        if let Some(path) = address
+            .strip_prefix("unix://")
+            .or_else(|| address.strip_prefix("unixgram://"))
+        {
+            Ok(StatsdAddr::UnixDatagram(PathBuf::from(path)))
+        } else if let Some(path) = address.strip_prefix("unixstream://") {
+            Ok(StatsdAddr::UnixStream(PathBuf::from(path)))
+        } else {
+            let address = address.strip_prefix("udp://").unwrap_or(address);
+            resolve_socket_addr(address).map(StatsdAddr::Udp)
+        }
+    }
+}
+
+/// A value that can be resolved to a `StatsdAddr`.
+///
+/// This is implemented for every `std::net::ToSocketAddrs` type, which are
+/// resolved to UDP addresses, and for `StatsdAddr` itself. To use a unix domain
+/// socket, parse a `unix://` address into a `StatsdAddr`. A string with a
+/// scheme that wasn't parsed fails to resolve with an error saying so.
+pub trait ToStatsdAddr {
+    fn to_statsd_addr(&self) -> Result<StatsdAddr, StatsdError>;
+}
+
+fn resolve_socket_addr<T: ToSocketAddrs + ?Sized>(address: &T) -> Result<SocketAddr, StatsdError> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| StatsdError::AddrParseError("could not parse address".to_string()))
+}
+
+impl ToStatsdAddr for StatsdAddr {
+    fn to_statsd_addr(&self) -> Result<StatsdAddr, StatsdError> {
+        Ok(self.clone())
+    }
+}
+
+impl<T: ToSocketAddrs + ?Sized> ToStatsdAddr for T {
+    fn to_statsd_addr(&self) -> Result<StatsdAddr, StatsdError> {
+        // A `unix://` or `udp://` string gets here as a host name, which can
+        // only fail to resolve, so point at the parse that handles schemes.
+        resolve_socket_addr(self).map(StatsdAddr::Udp).map_err(|e| {
+            StatsdError::AddrParseError(format!(
+                "could not resolve address: {}. Addresses with a scheme, like \
+                 `unix:///path`, must be parsed with `.parse::<StatsdAddr>()`",
+                e
+            ))
+        })
+    }
+}
+
+/// The unit durations are sent in by `time_distribution` and
+/// `time_distribution_async`.
+///
+/// Durations are sent with fractional precision, so sub-millisecond
+/// operations aren't reported as 0. Timers sent by `time` and friends are
+/// always in milliseconds, since that's the unit of the DogStatsD `ms` type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DurationUnit {
+    Seconds,
+    #[default]
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl DurationUnit {
+    /// A duration in this unit.
+    pub fn convert(self, duration: time::Duration) -> f64 {
+        match self {
+            DurationUnit::Seconds => duration.as_secs_f64(),
+            DurationUnit::Milliseconds => duration.as_secs_f64() * 1e3,
+            DurationUnit::Microseconds => duration.as_secs_f64() * 1e6,
+            DurationUnit::Nanoseconds => duration.as_nanos() as f64,
+        }
+    }
+}
+
+/// A measured duration as the fractional milliseconds a timer is sent with.
+pub(crate) fn timer_value(duration: time::Duration) -> f64 {
+    DurationUnit::Milliseconds.convert(duration)
+}
+
+/// The tags `time_result` and `time_async_result` add to a timer depending on
+/// whether the timed code returned `Ok` or `Err`.
+///
+/// Defaults to `status:ok` and `status:error`. An empty tag isn't sent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutcomeTags {
+    pub ok: String,
+    pub error: String,
+}
+
+impl Default for OutcomeTags {
+    fn default() -> Self {
+        OutcomeTags {
+            ok: "status:ok".into(),
+            error: "status:error".into(),
+        }
+    }
+}
+
+/// A config to build a statsd Client.  The address field should implement `ToStatsdAddr`,
+/// which covers the usual `std::net::ToSocketAddrs` types as well as a `StatsdAddr`
+/// parsed from a `unix://` socket path.
 /// See https://doc.rust-lang.org/std/net/trait.ToSocketAddrs.html.
 ///
 /// This type admits a builder pattern that's used like this:
@@ -37,6 +245,78 @@ pub struct ClientConfig<T> {
     pub address: T,
     pub prefix: Option<String>,
     pub constant_tags: Option<Vec<String>>,
+    /// Max size of a single packet. Defaults to 1432 bytes for UDP
+    /// and 8192 bytes for unix domain sockets. Larger payloads are dropped
+    /// and reported.
+    #[serde(default)]
+    pub max_packet_size: Option<usize>,
+    /// Queue metrics to a background thread that packs them into packets
+    /// instead of sending each one as it's recorded.
+    #[serde(default)]
+    pub buffered: bool,
+    /// How often a buffered client sends a partially filled packet.
+    /// Defaults to 100ms.
+    #[serde(default)]
+    pub buffer_flush_interval: Option<time::Duration>,
+    /// How many datagrams a buffered client or an `AsyncClient` may queue
+    /// for its sender before new ones are dropped. Defaults to 4096.
+    #[serde(default)]
+    pub buffer_queue_size: Option<usize>,
+    /// Merge counters, gauges and sets that share a name and tags on the
+    /// client, sending one value per flush interval.
+    #[serde(default)]
+    pub aggregation: bool,
+    /// How often an aggregating client sends its merged values.
+    /// Defaults to 2s.
+    #[serde(default)]
+    pub aggregation_flush_interval: Option<time::Duration>,
+    /// Also buffer histogram, distribution and timer samples on the client,
+    /// sending them as multi-value lines like `name:v1:v2:v3|h`.
+    /// Implies `aggregation`.
+    #[serde(default)]
+    pub extended_aggregation: bool,
+    /// The most samples an extended aggregating client keeps per metric
+    /// and tags over a flush interval. Unlimited by default.
+    #[serde(default)]
+    pub max_samples_per_context: Option<usize>,
+    /// Keep a uniform random sample of the values seen once
+    /// `max_samples_per_context` is reached, rather than the first ones.
+    #[serde(default)]
+    pub reservoir_sampling: bool,
+    /// Called with errors that would otherwise be discarded.
+    #[serde(skip)]
+    pub error_handler: Option<ErrorHandler>,
+    /// Periodically report `datadog.dogstatsd.client.*` metrics about what
+    /// the client sent and dropped. Enabled by default.
+    #[serde(default = "default_telemetry")]
+    pub telemetry: bool,
+    /// How often telemetry is reported. Defaults to 10s.
+    #[serde(default)]
+    pub telemetry_interval: Option<time::Duration>,
+    /// Detect the container ID from `/proc/self/cgroup` and `/proc/self/mountinfo`
+    /// and send it with every datagram as `|c:<id>`, for origin detection.
+    #[serde(default)]
+    pub origin_detection: bool,
+    /// A container ID to send with every datagram instead of detecting one.
+    #[serde(default)]
+    pub container_id: Option<String>,
+    /// The unit durations measured by `time_distribution` and
+    /// `time_distribution_async` are sent in. Timers are always sent in
+    /// milliseconds. Defaults to milliseconds.
+    #[serde(default)]
+    pub duration_unit: DurationUnit,
+    /// The tags `time_result` and `time_async_result` add for each outcome.
+    #[serde(default)]
+    pub outcome_tags: OutcomeTags,
+    /// When set, `time_result` and `time_async_result` also increment a
+    /// counter named after the timed metric with this suffix, e.g. `.errors`,
+    /// when the timed code returns `Err`.
+    #[serde(default)]
+    pub error_counter_suffix: Option<String>,
+}
+
+fn default_telemetry() -> bool {
+    true
 }
 
 impl<T> ClientConfig<T> {
@@ -48,10 +328,83 @@ impl<T> ClientConfig<T> {
     where
         T: ToSocketAddrs,
     {
-        self.address
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| StatsdError::AddrParseError("could not parse address".to_string()))
+        resolve_socket_addr(&self.address)
+    }
+
+    pub fn to_statsd_addr(&self) -> Result<StatsdAddr, StatsdError>
+    where
+        T: ToStatsdAddr,
+    {
+        self.address.to_statsd_addr()
+    }
+}
+
+/// Default DogStatsD port, used when `DD_DOGSTATSD_PORT` is not set.
+pub const DEFAULT_PORT: u16 = 8125;
+
+impl ClientConfig<StatsdAddr> {
+    /// Build a config from the standard Datadog environment variables.
+    ///
+    /// The address is taken from `DD_DOGSTATSD_URL` (e.g. `udp://localhost:8125` or
+    /// `unix:///var/run/datadog/dsd.socket`) if it is set, and otherwise from
+    /// `DD_AGENT_HOST` and `DD_DOGSTATSD_PORT`, defaulting to `localhost:8125`.
+    ///
+    /// `DD_ENV`, `DD_SERVICE`, `DD_VERSION` and `DD_ENTITY_ID` are added as the
+    /// `env:`, `service:`, `version:` and `dd.internal.entity_id:` constant tags.
+    ///
+    /// ```ignore
+    /// use datadog_statsd::{Client, ClientConfig};
+    ///
+    /// let mut config = ClientConfig::from_env()?;
+    /// config.prefix = Some("myapp".into());
+    /// let client = Client::new(&config)?;
+    /// ```
+    pub fn from_env() -> Result<ClientConfig<StatsdAddr>, StatsdError> {
+        Self::from_env_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_env_vars<F>(var: F) -> Result<ClientConfig<StatsdAddr>, StatsdError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        // Empty variables are treated as unset.
+        let var = |key: &str| var(key).filter(|v| !v.is_empty());
+
+        let address = match (var("DD_DOGSTATSD_URL"), var("DD_AGENT_HOST")) {
+            (Some(url), _) => url,
+            (None, Some(host)) if host.starts_with("unix") => host,
+            (None, host) => {
+                let host = host.unwrap_or_else(|| "localhost".into());
+                let port = match var("DD_DOGSTATSD_PORT") {
+                    Some(port) => port.parse::<u16>().map_err(|_| {
+                        StatsdError::AddrParseError(format!("invalid DD_DOGSTATSD_PORT: {}", port))
+                    })?,
+                    None => DEFAULT_PORT,
+                };
+                if host.contains(':') && !host.starts_with('[') {
+                    // Bare IPv6 addresses need brackets before a port is added.
+                    format!("[{}]:{}", host, port)
+                } else {
+                    format!("{}:{}", host, port)
+                }
+            }
+        };
+
+        let tags: Vec<String> = [
+            ("DD_ENV", "env"),
+            ("DD_SERVICE", "service"),
+            ("DD_VERSION", "version"),
+            ("DD_ENTITY_ID", "dd.internal.entity_id"),
+        ]
+        .iter()
+        .filter_map(|(key, tag)| var(key).map(|v| format!("{}:{}", tag, v)))
+        .collect();
+
+        let mut builder = ClientConfig::builder(address.parse::<StatsdAddr>()?);
+        if !tags.is_empty() {
+            builder = builder.constant_tags(tags.iter().map(|t| t.as_str()).collect());
+        }
+        Ok(builder.build())
     }
 }
 
@@ -59,6 +412,23 @@ pub struct ClientConfigBuilder<T> {
     address: T,
     prefix: Option<String>,
     constant_tags: Option<Vec<String>>,
+    max_packet_size: Option<usize>,
+    buffered: bool,
+    buffer_flush_interval: Option<time::Duration>,
+    buffer_queue_size: Option<usize>,
+    aggregation: bool,
+    aggregation_flush_interval: Option<time::Duration>,
+    extended_aggregation: bool,
+    max_samples_per_context: Option<usize>,
+    reservoir_sampling: bool,
+    error_handler: Option<ErrorHandler>,
+    telemetry: bool,
+    telemetry_interval: Option<time::Duration>,
+    origin_detection: bool,
+    container_id: Option<String>,
+    duration_unit: DurationUnit,
+    outcome_tags: OutcomeTags,
+    error_counter_suffix: Option<String>,
 }
 
 impl<T> ClientConfigBuilder<T> {
@@ -67,6 +437,23 @@ impl<T> ClientConfigBuilder<T> {
             address,
             prefix: None,
             constant_tags: None,
+            max_packet_size: None,
+            buffered: false,
+            buffer_flush_interval: None,
+            buffer_queue_size: None,
+            aggregation: false,
+            aggregation_flush_interval: None,
+            extended_aggregation: false,
+            max_samples_per_context: None,
+            reservoir_sampling: false,
+            error_handler: None,
+            telemetry: true,
+            telemetry_interval: None,
+            origin_detection: false,
+            container_id: None,
+            duration_unit: DurationUnit::default(),
+            outcome_tags: OutcomeTags::default(),
+            error_counter_suffix: None,
         }
     }
 
@@ -80,69 +467,159 @@ impl<T> ClientConfigBuilder<T> {
         self
     }
 
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    pub fn buffered(mut self, buffered: bool) -> Self {
+        self.buffered = buffered;
+        self
+    }
+
+    pub fn buffer_flush_interval(mut self, buffer_flush_interval: time::Duration) -> Self {
+        self.buffer_flush_interval = Some(buffer_flush_interval);
+        self
+    }
+
+    pub fn buffer_queue_size(mut self, buffer_queue_size: usize) -> Self {
+        self.buffer_queue_size = Some(buffer_queue_size);
+        self
+    }
+
+    pub fn aggregation(mut self, aggregation: bool) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    pub fn aggregation_flush_interval(
+        mut self,
+        aggregation_flush_interval: time::Duration,
+    ) -> Self {
+        self.aggregation_flush_interval = Some(aggregation_flush_interval);
+        self
+    }
+
+    pub fn extended_aggregation(mut self, extended_aggregation: bool) -> Self {
+        self.extended_aggregation = extended_aggregation;
+        self
+    }
+
+    pub fn max_samples_per_context(mut self, max_samples_per_context: usize) -> Self {
+        self.max_samples_per_context = Some(max_samples_per_context);
+        self
+    }
+
+    pub fn reservoir_sampling(mut self, reservoir_sampling: bool) -> Self {
+        self.reservoir_sampling = reservoir_sampling;
+        self
+    }
+
+    pub fn error_handler<F>(mut self, error_handler: F) -> Self
+    where
+        F: Fn(&StatsdError) + Send + Sync + 'static,
+    {
+        self.error_handler = Some(ErrorHandler::new(error_handler));
+        self
+    }
+
+    pub fn telemetry(mut self, telemetry: bool) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    pub fn telemetry_interval(mut self, telemetry_interval: time::Duration) -> Self {
+        self.telemetry_interval = Some(telemetry_interval);
+        self
+    }
+
+    pub fn origin_detection(mut self, origin_detection: bool) -> Self {
+        self.origin_detection = origin_detection;
+        self
+    }
+
+    pub fn container_id(mut self, container_id: &str) -> Self {
+        self.container_id = Some(container_id.into());
+        self
+    }
+
+    pub fn duration_unit(mut self, duration_unit: DurationUnit) -> Self {
+        self.duration_unit = duration_unit;
+        self
+    }
+
+    pub fn outcome_tags(mut self, ok: &str, error: &str) -> Self {
+        self.outcome_tags = OutcomeTags {
+            ok: ok.into(),
+            error: error.into(),
+        };
+        self
+    }
+
+    pub fn error_counter_suffix(mut self, error_counter_suffix: &str) -> Self {
+        self.error_counter_suffix = Some(error_counter_suffix.into());
+        self
+    }
+
     pub fn build(self) -> ClientConfig<T> {
         ClientConfig {
             address: self.address,
             prefix: self.prefix,
             constant_tags: self.constant_tags,
+            max_packet_size: self.max_packet_size,
+            buffered: self.buffered,
+            buffer_flush_interval: self.buffer_flush_interval,
+            buffer_queue_size: self.buffer_queue_size,
+            aggregation: self.aggregation,
+            aggregation_flush_interval: self.aggregation_flush_interval,
+            extended_aggregation: self.extended_aggregation,
+            max_samples_per_context: self.max_samples_per_context,
+            reservoir_sampling: self.reservoir_sampling,
+            error_handler: self.error_handler,
+            telemetry: self.telemetry,
+            telemetry_interval: self.telemetry_interval,
+            origin_detection: self.origin_detection,
+            container_id: self.container_id,
+            duration_unit: self.duration_unit,
+            outcome_tags: self.outcome_tags,
+            error_counter_suffix: self.error_counter_suffix,
         }
     }
 }
 
-struct InternalClient {
-    socket: UdpSocket,
-    socket_addr: SocketAddr,
+pub(crate) struct InternalClient {
+    transport: Box<dyn Transport>,
+    max_packet_size: usize,
     prefix: String,
     constant_tags: Vec<String>,
+    aggregator: Option<Aggregator>,
+    error_handler: Option<ErrorHandler>,
+    telemetry: Option<Arc<Telemetry>>,
+    telemetry_tags: Vec<String>,
+    // Whether the transport only queues datagrams for a worker, which counts
+    // the packets it writes itself.
+    queued: bool,
+    container_id: Option<String>,
+    duration_unit: DurationUnit,
+    outcome_tags: OutcomeTags,
+    error_counter_suffix: Option<String>,
+    // Dropping this stops the background flusher thread.
+    _stop_flusher: Option<mpsc::Sender<()>>,
 }
 
-/// Client socket for statsd servers.
-///
-/// After creating a metric you can use `Client`
-/// to send metrics to the configured statsd server.
-///
-/// # Example
-///
-/// Creating a client and sending metrics is easy.
-///
-/// ```ignore
-/// use datadog_statsd::{Client, ClientConfig};
-///
-/// let config = ClientConfig::builder(("127.0.0.1", 8125))
-///     .prefix("myapp")
-///     .constant_tags(vec!["sometag", "some:othertag"])
-///     .build();
-///
-/// let client = Client::new(&config);
-/// client.incr("some.metric.completed");
-/// ```
-pub struct Client {
-    client: Arc<InternalClient>,
-}
-
-impl Clone for Client {
-    fn clone(&self) -> Self {
-        Self {
-            client: Arc::clone(&self.client),
-        }
-    }
-}
-
-impl Client {
-    /// Construct a new statsd client given a client config
-    pub fn new<T: ToSocketAddrs>(client_config: &ClientConfig<T>) -> Result<Client, StatsdError> {
-        let socket_addr = client_config.to_socket_addr()?;
-
-        // Bind to a generic port as we'll only be writing on this
-        // socket.
-        let socket = if socket_addr.is_ipv4() {
-            UdpSocket::bind("0.0.0.0:0")?
-        } else {
-            UdpSocket::bind("[::]:0")?
-        };
-        let internal_client = InternalClient {
-            socket,
-            socket_addr,
+impl InternalClient {
+    fn new<A>(
+        transport: Box<dyn Transport>,
+        max_packet_size: usize,
+        transport_name: &str,
+        client_config: &ClientConfig<A>,
+        telemetry: Option<Arc<Telemetry>>,
+        queued: bool,
+        stop_flusher: Option<mpsc::Sender<()>>,
+    ) -> Self {
+        InternalClient {
+            transport,
+            max_packet_size,
             prefix: match &client_config.prefix {
                 Some(prefix) => prefix.to_string(),
                 _ => "".into(),
@@ -151,317 +628,507 @@ impl Client {
                 Some(tags) => tags.iter().map(|x| x.to_string()).collect(),
                 None => vec![],
             },
-        };
-        Ok(Client {
-            client: Arc::new(internal_client),
-        })
+            aggregator: if client_config.extended_aggregation {
+                Some(Aggregator::extended(SampleOptions {
+                    max_samples_per_context: client_config.max_samples_per_context,
+                    reservoir_sampling: client_config.reservoir_sampling,
+                }))
+            } else if client_config.aggregation {
+                Some(Aggregator::default())
+            } else {
+                None
+            },
+            error_handler: client_config.error_handler.clone(),
+            telemetry,
+            telemetry_tags: vec![
+                "client:rust".to_string(),
+                format!("client_version:{}", env!("CARGO_PKG_VERSION")),
+                format!("client_transport:{}", transport_name),
+            ],
+            queued,
+            container_id: match &client_config.container_id {
+                Some(container_id) => Some(container_id.clone()),
+                None if client_config.origin_detection => origin::detect_container_id(),
+                None => None,
+            },
+            duration_unit: client_config.duration_unit,
+            outcome_tags: client_config.outcome_tags.clone(),
+            error_counter_suffix: client_config.error_counter_suffix.clone(),
+            _stop_flusher: stop_flusher,
+        }
     }
 
-    /// Increment a metric by 1
-    ///
-    /// ```ignore
-    /// # Increment a given metric by 1.
-    /// client.incr("metric.completed", tags);
-    /// ```
-    ///
-    /// This modifies a counter with an effective sampling
-    /// rate of 1.0.
-    pub fn incr(&self, metric: &str, tags: Option<&Vec<&str>>) {
-        self.count(metric, 1.0, tags);
+    fn prepare<T: AsRef<str>>(&self, data: T) -> String {
+        if self.prefix.is_empty() {
+            data.as_ref().to_string()
+        } else {
+            format!("{}.{}", self.prefix, data.as_ref())
+        }
     }
 
-    /// Decrement a metric by -1
-    ///
-    /// ```ignore
-    /// # Decrement a given metric by 1
-    /// client.decr("metric.completed", tags);
-    /// ```
-    ///
-    /// This modifies a counter with an effective sampling
-    /// rate of 1.0.
-    pub fn decr(&self, metric: &str, tags: Option<&Vec<&str>>) {
-        self.count(metric, -1.0, tags);
+    fn prepare_with_tags<T: AsRef<str>>(&self, data: T, tags: Option<&Vec<&str>>) -> String {
+        self.append_tags(self.prepare(data), tags)
     }
 
-    /// Modify a counter by `value`.
-    ///
-    /// Will increment or decrement a counter by `value` with
-    /// a sampling rate of 1.0.
-    ///
-    /// ```ignore
-    /// // Increment by 12
-    /// client.count("metric.completed", 12.0, tags);
-    /// ```
-    pub fn count(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
-        let data = self.prepare_with_tags(format!("{}:{}|c", metric, value), tags);
-        self.send(data);
+    fn prepare_with_timestamp<T: AsRef<str>>(
+        &self,
+        data: T,
+        tags: Option<&Vec<&str>>,
+        timestamp: time::SystemTime,
+    ) -> String {
+        let data = self.join_tags(self.prepare(data), tags);
+        self.append_origin(format!("{}|T{}", data, unix_timestamp(timestamp)))
     }
 
-    /// Modify a counter by `value` only x% of the time.
-    ///
-    /// Will increment or decrement a counter by `value` with
-    /// a custom sampling rate.
-    ///
-    ///
-    /// ```ignore
-    /// // Increment by 4 50% of the time.
-    /// client.sampled_count("metric.completed", 4, 0.5, tags);
-    /// ```
-    pub fn sampled_count(&self, metric: &str, value: f64, rate: f64, tags: Option<&Vec<&str>>) {
-        if rand::random::<f64>() >= rate {
-            return;
-        }
-        let data = self.prepare_with_tags(format!("{}:{}|c|@{}", metric, value, rate), tags);
-        self.send(data);
+    fn append_tags<T: AsRef<str>>(&self, data: T, tags: Option<&Vec<&str>>) -> String {
+        self.append_origin(self.join_tags(data, tags))
     }
 
-    /// Set a gauge value.
-    ///
-    /// ```ignore
-    /// // set a gauge to 9001
-    /// client.gauge("power_level.observed", 9001.0, tags);
-    /// ```
-    pub fn gauge(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
-        let data = self.prepare_with_tags(format!("{}:{}|g", metric, value), tags);
-        self.send(data);
+    fn join_tags<T: AsRef<str>>(&self, data: T, tags: Option<&Vec<&str>>) -> String {
+        if self.constant_tags.is_empty() && tags.is_none() {
+            data.as_ref().to_string()
+        } else {
+            let mut all_tags = self.constant_tags.clone();
+            match tags {
+                Some(v) => {
+                    for tag in v {
+                        all_tags.push(tag.to_string());
+                    }
+                }
+                None => {
+                    // nothing to do
+                }
+            }
+            format!("{}|#{}", data.as_ref(), all_tags.join(","))
+        }
     }
 
-    /// Send a timer value.
-    ///
-    /// The value is expected to be in ms.
-    ///
-    /// ```ignore
-    /// // pass a duration value
-    /// client.timer("response.duration", 10.123, tags);
-    /// ```
-    pub fn timer(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
-        let data = self.prepare_with_tags(format!("{}:{}|ms", metric, value), tags);
-        self.send(data);
+    /// Format a service check, whose message goes after the tags.
+    fn format_service_check(
+        &self,
+        service_check: &ServiceCheck,
+        tags: Option<&Vec<&str>>,
+    ) -> String {
+        let mut data = self.join_tags(service_check.format(), tags);
+        if let Some(message) = service_check.format_message() {
+            data = format!("{}|{}", data, message);
+        }
+        self.append_origin(data)
     }
 
-    /// Time a block of code.
-    ///
-    /// The passed closure will be timed and executed. The block's
-    /// duration will be sent as a metric.
-    ///
-    /// ```ignore
-    /// // pass a duration value
-    /// client.time("response.duration", tags, || {
-    ///   // Your code here.
-    /// });
-    /// ```
-    pub fn time<F, R>(&self, metric: &str, tags: Option<&Vec<&str>>, callable: F) -> R
-    where
-        F: FnOnce() -> R,
-    {
-        let start = time::Instant::now();
-        let return_val = callable();
-        let used = start.elapsed();
-        let data = self.prepare_with_tags(format!("{}:{}|ms", metric, used.as_millis()), tags);
-        self.send(data);
-        return_val
+    /// Append the container ID field, which goes after the tags and timestamp.
+    fn append_origin<T: AsRef<str>>(&self, data: T) -> String {
+        match &self.container_id {
+            Some(container_id) => format!("{}|c:{}", data.as_ref(), container_id),
+            None => data.as_ref().to_string(),
+        }
     }
 
-    /// Time an async block of code.
-    /// The passed future will be `await`ed on, timed, and the result returned, the time
-    /// having passed being sent as a "time" metric.
-    pub async fn time_async<F, O>(&self, metric: &str, tags: Option<&Vec<&str>>, f: F) -> O
-    where
-        F: Future<Output = O>,
-    {
-        let start = time::Instant::now();
-        let return_val = f.await;
-        let used = start.elapsed();
-        let data = self.prepare_with_tags(format!("{}:{}|ms", metric, used.as_millis()), tags);
-        self.send(data);
-        return_val
+    fn send<D: AsRef<[u8]>>(&self, data: D) {
+        let data = data.as_ref();
+        if data.len() > self.max_packet_size {
+            self.record(|t| t.packet_dropped(data.len()));
+            return self.report(StatsdError::PacketTooLarge {
+                size: data.len(),
+                max: self.max_packet_size,
+            });
+        }
+        match self.transport.send(data) {
+            Ok(_) if self.queued => {}
+            Ok(_) => self.record(|t| t.packet_sent(data.len())),
+            Err(e) => {
+                self.record(|t| t.packet_dropped(data.len()));
+                self.report(e.into());
+            }
+        }
     }
 
-    fn prepare<T: AsRef<str>>(&self, data: T) -> String {
-        if self.client.prefix.is_empty() {
-            data.as_ref().to_string()
-        } else {
-            format!("{}.{}", self.client.prefix, data.as_ref())
+    pub(crate) fn record<F: FnOnce(&Telemetry)>(&self, f: F) {
+        if let Some(telemetry) = &self.telemetry {
+            f(telemetry);
         }
     }
 
-    fn prepare_with_tags<T: AsRef<str>>(&self, data: T, tags: Option<&Vec<&str>>) -> String {
-        self.append_tags(self.prepare(data), tags)
+    /// Send the telemetry counted since the last flush.
+    pub(crate) fn flush_telemetry(&self) {
+        self.send_lines(self.telemetry_lines());
     }
 
-    fn append_tags<T: AsRef<str>>(&self, data: T, tags: Option<&Vec<&str>>) -> String {
-        if self.client.constant_tags.is_empty() && tags.is_none() {
-            data.as_ref().to_string()
-        } else {
-            let mut all_tags = self.client.constant_tags.clone();
-            match tags {
-                Some(v) => {
-                    for tag in v {
-                        all_tags.push(tag.to_string());
-                    }
-                }
-                None => {
-                    // nothing to do
-                }
+    /// Take the telemetry counted since the last flush as tagged lines.
+    pub(crate) fn telemetry_lines(&self) -> Vec<String> {
+        let telemetry = match &self.telemetry {
+            Some(telemetry) => telemetry,
+            None => return vec![],
+        };
+        telemetry
+            .flush()
+            .into_iter()
+            .map(|(line, tag)| {
+                let mut tags: Vec<&str> = self.telemetry_tags.iter().map(|t| t.as_str()).collect();
+                tags.extend(tag.as_deref());
+                self.append_tags(line, Some(&tags))
+            })
+            .collect()
+    }
+
+    /// Send lines packed into as few packets as possible.
+    fn send_lines(&self, lines: Vec<String>) {
+        let mut builder = PacketBuilder::new(self.max_packet_size);
+        for data in lines {
+            self.pack(&mut builder, data.as_bytes());
+        }
+        if let Some(packet) = builder.finish() {
+            self.send(packet);
+        }
+    }
+
+    /// Add a line to a packet, sending the packet once it's full. A line
+    /// that can't fit in a packet is dropped and reported.
+    fn pack(&self, builder: &mut PacketBuilder, data: &[u8]) {
+        match builder.push(data) {
+            Ok(Some(packet)) => self.send(packet),
+            Ok(None) => {}
+            Err(e) => {
+                self.record(|t| t.packet_dropped(data.len()));
+                self.report(e);
             }
-            format!("{}|#{}", data.as_ref(), all_tags.join(","))
         }
     }
 
-    /// Send data along the UDP socket.
-    fn send(&self, data: String) {
-        let _ = self
-            .client
-            .socket
-            .send_to(data.as_bytes(), self.client.socket_addr);
+    fn flush(&self) {
+        self.flush_aggregates();
+        if let Err(e) = self.transport.flush() {
+            self.report(e.into());
+        }
     }
 
-    /// Get a pipeline struct that allows optimizes the number of UDP
-    /// packets used to send multiple metrics
-    ///
-    /// ```ignore
-    /// let mut pipeline = client.pipeline();
-    /// pipeline.incr("some.metric", 1);
-    /// pipeline.incr("other.metric", 1);
-    /// pipeline.send(&mut client);
-    /// ```
-    pub fn pipeline(&self) -> Pipeline {
-        Pipeline::new()
+    pub(crate) fn report(&self, error: StatsdError) {
+        if let Some(error_handler) = &self.error_handler {
+            error_handler.handle(&error);
+        }
     }
 
-    /// Send a histogram value.
-    ///
-    /// ```ignore
-    /// // pass response size value
-    /// client.histogram("response.size", 128.0, tags);
-    /// ```
-    pub fn histogram(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
-        let data = self.prepare_with_tags(format!("{}:{}|h", metric, value), tags);
-        self.send(data);
+    /// Whether `metric` is a valid name, reporting it if not.
+    fn check_metric_name(&self, metric: &str) -> bool {
+        match validate_metric_name(metric) {
+            Ok(()) => true,
+            Err(e) => {
+                self.report(e);
+                false
+            }
+        }
     }
 
-    /// Send a event.
-    ///
-    /// ```ignore
-    /// // pass a app start event
-    /// client.event("MyApp Start", "MyApp Details", AlertType::Info, &Some(vec!["tag1", "tag2:test"]));
-    /// ```
-    pub fn event(&self, title: &str, text: &str, alert_type: AlertType, tags: Option<&Vec<&str>>) {
-        let mut d = vec![];
-        d.push(format!("_e{{{},{}}}:{}", title.len(), text.len(), title));
-        d.push(text.to_string());
-        if alert_type != AlertType::Info {
-            d.push(format!("t:{}", alert_type.to_string().to_lowercase()))
+    /// Send everything aggregated since the last flush, packed into as few
+    /// packets as possible.
+    pub(crate) fn flush_aggregates(&self) {
+        self.send_lines(self.aggregate_lines());
+    }
+
+    /// Take everything aggregated since the last flush as lines.
+    pub(crate) fn aggregate_lines(&self) -> Vec<String> {
+        match &self.aggregator {
+            Some(aggregator) => aggregator
+                .flush()
+                .iter()
+                .flat_map(|aggregate| self.format_aggregate(aggregate))
+                .collect(),
+            None => vec![],
         }
-        let event_with_tags = self.append_tags(d.join("|"), tags);
-        self.send(event_with_tags)
     }
 
-    /// Send a service check.
-    ///
-    /// ```ignore
-    /// // pass a app status
-    /// client.service_check("MyApp", ServiceCheckStatus::Ok, &Some(vec!["tag1", "tag2:test"]));
-    /// ```
-    pub fn service_check(
-        &self,
-        service_check_name: &str,
-        status: ServiceCheckStatus,
-        tags: Option<&Vec<&str>>,
-    ) {
-        let mut d = vec![];
-        let status_code = (status as u32).to_string();
-        d.push("_sc");
-        d.push(service_check_name);
-        d.push(&status_code);
-        let sc_with_tags = self.append_tags(d.join("|"), tags);
-        self.send(sc_with_tags)
+    /// Format an aggregate as one or more lines, splitting multi-value
+    /// aggregates so that each line fits in a packet.
+    fn format_aggregate(&self, aggregate: &Aggregate) -> Vec<String> {
+        let tags: Option<Vec<&str>> = aggregate
+            .tags
+            .as_ref()
+            .map(|v| v.iter().map(|t| t.as_str()).collect());
+        let format = |values: &[String]| {
+            self.prepare_with_tags(
+                format!(
+                    "{}:{}{}",
+                    aggregate.metric,
+                    values.join(":"),
+                    aggregate.suffix
+                ),
+                tags.as_ref(),
+            )
+        };
+        let overhead = format(&[]).len();
+        let mut lines = vec![];
+        let mut start = 0;
+        let mut len = 0;
+        for (i, value) in aggregate.values.iter().enumerate() {
+            let sep = if i == start { 0 } else { 1 };
+            if i > start && overhead + len + sep + value.len() > self.max_packet_size {
+                lines.push(format(&aggregate.values[start..i]));
+                start = i;
+                len = value.len();
+            } else {
+                len += sep + value.len();
+            }
+        }
+        if start < aggregate.values.len() {
+            lines.push(format(&aggregate.values[start..]));
+        }
+        lines
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum AlertType {
-    Info,
-    Error,
-    Warning,
-    Success,
+impl Drop for InternalClient {
+    fn drop(&mut self) {
+        // Aggregates go out before the transport, which flushes itself on drop.
+        self.flush_aggregates();
+    }
 }
 
-impl fmt::Display for AlertType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+/// How often the background flusher thread does each kind of periodic work.
+pub(crate) struct FlushSchedule {
+    pub(crate) aggregation: Option<time::Duration>,
+    pub(crate) telemetry: Option<time::Duration>,
+}
+
+impl FlushSchedule {
+    pub(crate) fn new<A>(client_config: &ClientConfig<A>) -> Self {
+        let aggregation = client_config.aggregation || client_config.extended_aggregation;
+        FlushSchedule {
+            aggregation: if aggregation {
+                Some(
+                    client_config
+                        .aggregation_flush_interval
+                        .unwrap_or(DEFAULT_AGGREGATION_FLUSH_INTERVAL),
+                )
+            } else {
+                None
+            },
+            telemetry: if client_config.telemetry {
+                Some(
+                    client_config
+                        .telemetry_interval
+                        .unwrap_or(DEFAULT_TELEMETRY_INTERVAL),
+                )
+            } else {
+                None
+            },
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum ServiceCheckStatus {
-    Ok = 0,
-    Warning = 1,
-    Critical = 2,
-    Unknown = 3,
+/// Flush aggregates and telemetry on their schedules until the client is dropped.
+fn spawn_flusher(client: Weak<InternalClient>, stop: mpsc::Receiver<()>, schedule: FlushSchedule) {
+    let _ = thread::Builder::new()
+        .name("dogstatsd-flusher".into())
+        .spawn(move || {
+            let now = time::Instant::now();
+            let mut next_aggregation = schedule.aggregation.map(|i| now + i);
+            let mut next_telemetry = schedule.telemetry.map(|i| now + i);
+            loop {
+                let next = match (next_aggregation, next_telemetry) {
+                    (Some(a), Some(t)) => a.min(t),
+                    (Some(next), None) | (None, Some(next)) => next,
+                    (None, None) => return,
+                };
+                let timeout = next.saturating_duration_since(time::Instant::now());
+                match stop.recv_timeout(timeout) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        let client = match client.upgrade() {
+                            Some(client) => client,
+                            None => return,
+                        };
+                        let now = time::Instant::now();
+                        if let (Some(next), Some(interval)) =
+                            (next_aggregation, schedule.aggregation)
+                        {
+                            if next <= now {
+                                client.flush_aggregates();
+                                next_aggregation = Some(now + interval);
+                            }
+                        }
+                        if let (Some(next), Some(interval)) = (next_telemetry, schedule.telemetry) {
+                            if next <= now {
+                                client.flush_telemetry();
+                                next_telemetry = Some(now + interval);
+                            }
+                        }
+                    }
+                    _ => return,
+                }
+            }
+        });
 }
 
-pub struct Pipeline {
-    stats: VecDeque<String>,
-    max_udp_size: usize,
+/// Client socket for statsd servers.
+///
+/// After creating a metric you can use `Client`
+/// to send metrics to the configured statsd server.
+///
+/// # Example
+///
+/// Creating a client and sending metrics is easy.
+///
+/// ```ignore
+/// use datadog_statsd::{Client, ClientConfig};
+///
+/// let config = ClientConfig::builder(("127.0.0.1", 8125))
+///     .prefix("myapp")
+///     .constant_tags(vec!["sometag", "some:othertag"])
+///     .build();
+///
+/// let client = Client::new(&config);
+/// client.incr("some.metric.completed");
+/// ```
+pub struct Client {
+    pub(crate) client: Arc<InternalClient>,
 }
 
-impl Default for Pipeline {
-    fn default() -> Self {
-        Self::new()
+impl Clone for Client {
+    fn clone(&self) -> Self {
+        Self {
+            client: Arc::clone(&self.client),
+        }
     }
 }
 
-impl Pipeline {
-    pub fn new() -> Pipeline {
-        Pipeline {
-            stats: VecDeque::new(),
-            max_udp_size: 512,
-        }
+impl Client {
+    /// Construct a new statsd client given a client config
+    pub fn new<T: ToStatsdAddr>(client_config: &ClientConfig<T>) -> Result<Client, StatsdError> {
+        let addr = client_config.to_statsd_addr()?;
+        let transport_name = match addr {
+            StatsdAddr::Udp(_) => "udp",
+            StatsdAddr::UnixDatagram(_) => "uds",
+            StatsdAddr::UnixStream(_) => "uds-stream",
+        };
+        let transport = transport::connect(addr)?;
+        Ok(Self::from_boxed_transport(
+            transport,
+            transport_name,
+            client_config,
+        ))
     }
 
-    /// Set max UDP packet size
+    /// Construct a new statsd client that writes to an explicit transport.
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
+    /// The prefix, constant tags and max packet size are taken from the config,
+    /// while its address is ignored.
     ///
-    /// let mut pipe = Pipeline::new();
-    /// pipe.set_max_udp_size(128);
+    /// ```ignore
+    /// use datadog_statsd::{Client, ClientConfig};
+    /// use datadog_statsd::transport::UdpTransport;
+    ///
+    /// let config = ClientConfig::builder(()).prefix("myapp").build();
+    /// let transport = UdpTransport::connect("127.0.0.1:8125".parse().unwrap()).unwrap();
+    /// let client = Client::with_transport(transport, &config);
     /// ```
-    pub fn set_max_udp_size(&mut self, max_udp_size: usize) {
-        self.max_udp_size = max_udp_size;
+    pub fn with_transport<T, A>(transport: T, client_config: &ClientConfig<A>) -> Client
+    where
+        T: Transport + 'static,
+    {
+        Self::from_boxed_transport(Box::new(transport), "custom", client_config)
+    }
+
+    fn from_boxed_transport<A>(
+        transport: Box<dyn Transport>,
+        transport_name: &str,
+        client_config: &ClientConfig<A>,
+    ) -> Client {
+        let max_packet_size = client_config
+            .max_packet_size
+            .unwrap_or_else(|| transport.max_packet_size());
+        let telemetry = Telemetry::from_config(client_config);
+        let transport: Box<dyn Transport> = if client_config.buffered {
+            let flush_interval = client_config
+                .buffer_flush_interval
+                .unwrap_or(DEFAULT_BUFFER_FLUSH_INTERVAL);
+            Box::new(BufferedTransport::spawn(
+                transport,
+                max_packet_size,
+                flush_interval,
+                client_config
+                    .buffer_queue_size
+                    .unwrap_or(DEFAULT_BUFFER_QUEUE_SIZE),
+                client_config.error_handler.clone(),
+                telemetry.clone(),
+            ))
+        } else {
+            transport
+        };
+        let schedule = FlushSchedule::new(client_config);
+        let (stop_flusher, flusher_stopped) =
+            if schedule.aggregation.is_some() || schedule.telemetry.is_some() {
+                let (stop, stopped) = mpsc::channel();
+                (Some(stop), Some(stopped))
+            } else {
+                (None, None)
+            };
+        let client = Arc::new(InternalClient::new(
+            transport,
+            max_packet_size,
+            transport_name,
+            client_config,
+            telemetry,
+            client_config.buffered,
+            stop_flusher,
+        ));
+        if let Some(stopped) = flusher_stopped {
+            spawn_flusher(Arc::downgrade(&client), stopped, schedule);
+        }
+        Client { client }
+    }
+
+    /// Construct a client without a background flusher, for callers that
+    /// flush aggregates and telemetry on their own schedule. The transport
+    /// is taken to queue datagrams, so the packets it sends are counted
+    /// with `telemetry` where they're written. The config's `buffered`
+    /// setting is ignored.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn unscheduled<A>(
+        transport: Box<dyn Transport>,
+        transport_name: &str,
+        client_config: &ClientConfig<A>,
+        telemetry: Option<Arc<Telemetry>>,
+    ) -> Client {
+        let max_packet_size = client_config
+            .max_packet_size
+            .unwrap_or_else(|| transport.max_packet_size());
+        let client = InternalClient::new(
+            transport,
+            max_packet_size,
+            transport_name,
+            client_config,
+            telemetry,
+            true,
+            None,
+        );
+        Client {
+            client: Arc::new(client),
+        }
     }
 
     /// Increment a metric by 1
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
-    ///
-    /// let mut pipe = Pipeline::new();
-    /// // Increment a given metric by 1.
-    /// pipe.incr("metric.completed");
+    /// ```ignore
+    /// # Increment a given metric by 1.
+    /// client.incr("metric.completed", tags);
     /// ```
     ///
     /// This modifies a counter with an effective sampling
     /// rate of 1.0.
-    pub fn incr(&mut self, metric: &str) {
-        self.count(metric, 1.0);
+    pub fn incr(&self, metric: &str, tags: Option<&Vec<&str>>) {
+        self.count(metric, 1.0, tags);
     }
 
     /// Decrement a metric by -1
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
-    ///
-    /// let mut pipe = Pipeline::new();
-    /// // Decrement a given metric by 1
-    /// pipe.decr("metric.completed");
+    /// ```ignore
+    /// # Decrement a given metric by 1
+    /// client.decr("metric.completed", tags);
     /// ```
     ///
     /// This modifies a counter with an effective sampling
     /// rate of 1.0.
-    pub fn decr(&mut self, metric: &str) {
-        self.count(metric, -1.0);
+    pub fn decr(&self, metric: &str, tags: Option<&Vec<&str>>) {
+        self.count(metric, -1.0, tags);
     }
 
     /// Modify a counter by `value`.
@@ -469,66 +1136,136 @@ impl Pipeline {
     /// Will increment or decrement a counter by `value` with
     /// a sampling rate of 1.0.
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
-    ///
-    /// let mut pipe = Pipeline::new();
+    /// ```ignore
     /// // Increment by 12
-    /// pipe.count("metric.completed", 12.0);
+    /// client.count("metric.completed", 12.0, tags);
     /// ```
-    pub fn count(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|c", metric, value);
-        self.stats.push_back(data);
-    }
-
-    /// Modify a counter by `value` only x% of the time.
-    ///
+    pub fn count(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Count));
+        if let Some(aggregator) = &self.client.aggregator {
+            return aggregator.count(metric, value, tags);
+        }
+        let data = self.prepare_with_tags(format!("{}:{}|c", metric, value), tags);
+        self.send(data);
+    }
+
+    /// Modify a counter by `value` at a point in the past.
+    ///
+    /// The timestamp is sent as `|T<unix seconds>`, so that metrics
+    /// collected offline can be replayed. Timestamped points are never
+    /// aggregated by the client.
+    ///
+    /// ```ignore
+    /// // Record 12 completions an hour ago
+    /// let timestamp = SystemTime::now() - Duration::from_secs(3600);
+    /// client.count_with_timestamp("metric.completed", 12.0, timestamp, tags);
+    /// ```
+    pub fn count_with_timestamp(
+        &self,
+        metric: &str,
+        value: f64,
+        timestamp: time::SystemTime,
+        tags: Option<&Vec<&str>>,
+    ) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Count));
+        let data =
+            self.client
+                .prepare_with_timestamp(format!("{}:{}|c", metric, value), tags, timestamp);
+        self.send(data);
+    }
+
+    /// Modify a counter by `value` only x% of the time.
+    ///
     /// Will increment or decrement a counter by `value` with
     /// a custom sampling rate.
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
     ///
-    /// let mut pipe = Pipeline::new();
+    /// ```ignore
     /// // Increment by 4 50% of the time.
-    /// pipe.sampled_count("metric.completed", 4.0, 0.5);
+    /// client.sampled_count("metric.completed", 4, 0.5, tags);
     /// ```
-    pub fn sampled_count(&mut self, metric: &str, value: f64, rate: f64) {
+    pub fn sampled_count(&self, metric: &str, value: f64, rate: f64, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
         if rand::random::<f64>() >= rate {
             return;
         }
-        let data = format!("{}:{}|c|@{}", metric, value, rate);
-        self.stats.push_back(data);
+        self.client.record(|t| t.metric(MetricType::Count));
+        let data = self.prepare_with_tags(format!("{}:{}|c|@{}", metric, value, rate), tags);
+        self.send(data);
     }
 
     /// Set a gauge value.
     ///
+    /// ```ignore
+    /// // set a gauge to 9001
+    /// client.gauge("power_level.observed", 9001.0, tags);
     /// ```
-    /// use datadog_statsd::client::Pipeline;
+    pub fn gauge(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Gauge));
+        if let Some(aggregator) = &self.client.aggregator {
+            return aggregator.gauge(metric, value, tags);
+        }
+        let data = self.prepare_with_tags(format!("{}:{}|g", metric, value), tags);
+        self.send(data);
+    }
+
+    /// Set a gauge value at a point in the past.
     ///
-    /// let mut pipe = Pipeline::new();
-    /// // set a gauge to 9001
-    /// pipe.gauge("power_level.observed", 9001.0);
+    /// The timestamp is sent as `|T<unix seconds>`, so that metrics
+    /// collected offline can be replayed. Timestamped points are never
+    /// aggregated by the client.
+    ///
+    /// ```ignore
+    /// // Record a gauge of 9001 an hour ago
+    /// let timestamp = SystemTime::now() - Duration::from_secs(3600);
+    /// client.gauge_with_timestamp("power_level.observed", 9001.0, timestamp, tags);
     /// ```
-    pub fn gauge(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|g", metric, value);
-        self.stats.push_back(data);
+    pub fn gauge_with_timestamp(
+        &self,
+        metric: &str,
+        value: f64,
+        timestamp: time::SystemTime,
+        tags: Option<&Vec<&str>>,
+    ) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Gauge));
+        let data =
+            self.client
+                .prepare_with_timestamp(format!("{}:{}|g", metric, value), tags, timestamp);
+        self.send(data);
     }
 
     /// Send a timer value.
     ///
     /// The value is expected to be in ms.
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
-    ///
-    /// let mut pipe = Pipeline::new();
+    /// ```ignore
     /// // pass a duration value
-    /// pipe.timer("response.duration", 10.123);
+    /// client.timer("response.duration", 10.123, tags);
     /// ```
-    pub fn timer(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|ms", metric, value);
-        self.stats.push_back(data);
+    pub fn timer(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Timing));
+        if let Some(aggregator) = self.extended_aggregator() {
+            return aggregator.timing(metric, value, tags);
+        }
+        let data = self.prepare_with_tags(format!("{}:{}|ms", metric, value), tags);
+        self.send(data);
     }
 
     /// Time a block of code.
@@ -536,402 +1273,2382 @@ impl Pipeline {
     /// The passed closure will be timed and executed. The block's
     /// duration will be sent as a metric.
     ///
-    /// ```
-    /// use datadog_statsd::client::Pipeline;
-    ///
-    /// let mut pipe = Pipeline::new();
+    /// ```ignore
     /// // pass a duration value
-    /// pipe.time("response.duration", || {
+    /// client.time("response.duration", tags, || {
     ///   // Your code here.
     /// });
     /// ```
-    pub fn time<F>(&mut self, metric: &str, callable: F)
+    pub fn time<F, R>(&self, metric: &str, tags: Option<&Vec<&str>>, callable: F) -> R
     where
-        F: FnOnce(),
+        F: FnOnce() -> R,
     {
         let start = time::Instant::now();
-        callable();
+        let return_val = callable();
         let used = start.elapsed();
-        let data = format!("{}:{}|ms", metric, used.as_millis());
-        self.stats.push_back(data);
+        self.timer(metric, timer_value(used), tags);
+        return_val
     }
 
-    /// Send a histogram value.
+    /// Start timing, returning a guard that sends the elapsed time as a
+    /// timer when it is dropped.
     ///
+    /// Unlike `time`, the timed code doesn't need to be in a closure, and
+    /// tags can be added once the outcome is known.
+    ///
+    /// ```ignore
+    /// let mut timer = client.start_timer("response.duration", tags);
+    /// let response = handle(request)?;
+    /// timer.add_tag("status:ok");
     /// ```
-    /// use datadog_statsd::client::Pipeline;
+    pub fn start_timer(&self, metric: &str, tags: Option<&Vec<&str>>) -> TimerGuard {
+        TimerGuard::new(self.clone(), metric, tags)
+    }
+
+    /// Time an async block of code.
+    /// The passed future will be `await`ed on, timed, and the result returned, the time
+    /// having passed being sent as a "time" metric.
+    pub async fn time_async<F, O>(&self, metric: &str, tags: Option<&Vec<&str>>, f: F) -> O
+    where
+        F: Future<Output = O>,
+    {
+        let start = time::Instant::now();
+        let return_val = f.await;
+        let used = start.elapsed();
+        self.timer(metric, timer_value(used), tags);
+        return_val
+    }
+
+    /// Time a block of code that returns a `Result`, tagging the timer with
+    /// the outcome.
     ///
-    /// let mut pipe = Pipeline::new();
-    /// // pass response size value
-    /// pipe.histogram("response.size", 128.0);
+    /// Like `time`, but the timer is also tagged with the configured
+    /// `outcome_tags`, `status:ok` or `status:error` by default, so success
+    /// and error latency can be told apart. If an `error_counter_suffix` is
+    /// configured, an `Err` also increments that counter.
+    ///
+    /// ```ignore
+    /// let user = client.time_result("db.query", tags, || db.find_user(id))?;
     /// ```
-    pub fn histogram(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|h", metric, value);
-        self.stats.push_back(data);
+    pub fn time_result<F, T, E>(
+        &self,
+        metric: &str,
+        tags: Option<&Vec<&str>>,
+        callable: F,
+    ) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let start = time::Instant::now();
+        let return_val = callable();
+        let used = start.elapsed();
+        self.send_outcome(metric, used, tags, return_val.is_ok());
+        return_val
     }
 
-    /// Send data along the UDP socket.
-    pub fn send(&mut self, client: &Client) {
-        let mut _data = String::new();
-        if let Some(data) = self.stats.pop_front() {
-            _data += client.prepare(&data).as_ref();
-            while !self.stats.is_empty() {
-                let stat = client.prepare(self.stats.pop_front().unwrap());
-                if data.len() + stat.len() + 1 > self.max_udp_size {
-                    client.send(_data.clone());
-                    _data.clear();
-                    _data += &stat;
-                } else {
-                    _data += "\n";
-                    _data += &stat;
-                }
-            }
+    /// Time an async block of code that returns a `Result`, tagging the timer
+    /// with the outcome like `time_result`.
+    pub async fn time_async_result<F, T, E>(
+        &self,
+        metric: &str,
+        tags: Option<&Vec<&str>>,
+        f: F,
+    ) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let start = time::Instant::now();
+        let return_val = f.await;
+        let used = start.elapsed();
+        self.send_outcome(metric, used, tags, return_val.is_ok());
+        return_val
+    }
+
+    fn send_outcome(&self, metric: &str, used: time::Duration, tags: Option<&Vec<&str>>, ok: bool) {
+        let outcome = if ok {
+            &self.client.outcome_tags.ok
+        } else {
+            &self.client.outcome_tags.error
+        };
+        let mut timer_tags = tags.cloned().unwrap_or_default();
+        if !outcome.is_empty() {
+            timer_tags.push(outcome);
         }
-        if !_data.is_empty() {
-            client.send(_data);
+        let timer_tags = Some(&timer_tags).filter(|t| !t.is_empty());
+        self.timer(metric, timer_value(used), timer_tags);
+        if let (false, Some(suffix)) = (ok, &self.client.error_counter_suffix) {
+            self.incr(&format!("{}{}", metric, suffix), tags);
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    extern crate rand;
-    use self::rand::distributions::{IndependentSample, Range};
-    use super::*;
-    use std::net::UdpSocket;
-    use std::str;
-    use std::sync::mpsc::sync_channel;
-    use std::thread;
+    /// Time a block of code, reporting the duration as a distribution.
+    ///
+    /// Like `time`, but the block's duration is sent as a distribution
+    /// so that percentiles are aggregated globally rather than per agent.
+    ///
+    /// ```ignore
+    /// client.time_distribution("response.duration", tags, || {
+    ///   // Your code here.
+    /// });
+    /// ```
+    pub fn time_distribution<F, R>(&self, metric: &str, tags: Option<&Vec<&str>>, callable: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let start = time::Instant::now();
+        let return_val = callable();
+        let used = start.elapsed();
+        self.distribution(metric, self.duration_value(used), tags);
+        return_val
+    }
 
-    static PORT: u16 = 8125;
+    /// Time an async block of code, reporting the duration as a distribution.
+    /// The passed future will be `await`ed on, timed, and the result returned, the time
+    /// having passed being sent as a distribution metric.
+    pub async fn time_distribution_async<F, O>(
+        &self,
+        metric: &str,
+        tags: Option<&Vec<&str>>,
+        f: F,
+    ) -> O
+    where
+        F: Future<Output = O>,
+    {
+        let start = time::Instant::now();
+        let return_val = f.await;
+        let used = start.elapsed();
+        self.distribution(metric, self.duration_value(used), tags);
+        return_val
+    }
 
-    // Generates random ports.
-    // Having random ports helps tests not collide over
-    // shared ports.
-    fn next_test_ip4() -> String {
-        let range = Range::new(0, 1000);
-        let mut rng = rand::thread_rng();
-        let port = PORT + range.ind_sample(&mut rng);
-        format!("127.0.0.1:{}", port)
+    pub(crate) fn prepare_with_tags<T: AsRef<str>>(
+        &self,
+        data: T,
+        tags: Option<&Vec<&str>>,
+    ) -> String {
+        self.client.prepare_with_tags(data, tags)
     }
 
-    // Makes a udpsocket that acts as a statsd server.
-    fn make_server(host: &str) -> UdpSocket {
-        UdpSocket::bind(host).ok().unwrap()
+    /// A measured duration in the client's configured unit, for distributions.
+    pub(crate) fn duration_value(&self, duration: time::Duration) -> f64 {
+        self.client.duration_unit.convert(duration)
     }
 
-    // Makes a `Client` with a prefix.
-    fn make_client(host: &str) -> Client {
-        let config = ClientConfig::builder(host).prefix("myapp").build();
-        Client::new(&config).unwrap()
+    fn append_tags<T: AsRef<str>>(&self, data: T, tags: Option<&Vec<&str>>) -> String {
+        self.client.append_tags(data, tags)
     }
 
-    // Makes a `Client` with no prefix.
-    fn make_client_without_prefix(host: &str) -> Client {
-        let config = ClientConfig::builder(host).build();
-        Client::new(&config).unwrap()
+    fn extended_aggregator(&self) -> Option<&Aggregator> {
+        self.client.aggregator.as_ref().filter(|a| a.is_extended())
     }
 
-    // Makes a client with prefix and constant tags.
-    fn make_client_with_prefix_and_constant_tags(host: &str) -> Client {
-        let config = ClientConfig::builder(host)
-            .prefix("myapp")
-            .constant_tags(vec!["tag1common", "tag2common:test"])
-            .build();
-        Client::new(&config).unwrap()
+    /// Send data along the transport.
+    fn send<D: AsRef<[u8]>>(&self, data: D) {
+        self.client.send(data);
     }
 
-    fn server_recv(server: UdpSocket) -> String {
-        let (serv_tx, serv_rx) = sync_channel(1);
-        let _t = thread::spawn(move || {
-            let mut buf = [0; 128];
-            let (len, _) = match server.recv_from(&mut buf) {
-                Ok(r) => r,
-                Err(_) => panic!("No response from test server."),
-            };
-            drop(server);
-            let bytes = Vec::from(&buf[0..len]);
-            serv_tx.send(bytes).unwrap();
-        });
+    /// Flush anything aggregated or buffered by the client.
+    ///
+    /// For a buffered client this blocks until every queued metric has been
+    /// written. Aggregating and buffered clients are also flushed when the
+    /// last clone is dropped.
+    pub fn flush(&self) {
+        self.client.flush();
+    }
 
-        let bytes = serv_rx.recv().ok().unwrap();
-        str::from_utf8(&bytes).unwrap().to_string()
+    /// Get a pipeline struct that allows optimizes the number of UDP
+    /// packets used to send multiple metrics
+    ///
+    /// ```ignore
+    /// let mut pipeline = client.pipeline();
+    /// pipeline.incr("some.metric", None);
+    /// pipeline.incr("other.metric", tags);
+    /// pipeline.send(&mut client);
+    /// ```
+    pub fn pipeline(&self) -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        pipeline.set_max_udp_size(self.client.max_packet_size);
+        pipeline
     }
 
-    #[test]
-    fn test_sending_gauge() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+    /// Send a histogram value.
+    ///
+    /// ```ignore
+    /// // pass response size value
+    /// client.histogram("response.size", 128.0, tags);
+    /// ```
+    pub fn histogram(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Histogram));
+        if let Some(aggregator) = self.extended_aggregator() {
+            return aggregator.histogram(metric, value, tags);
+        }
+        let data = self.prepare_with_tags(format!("{}:{}|h", metric, value), tags);
+        self.send(data);
+    }
 
-        client.gauge("metric", 9.1, None);
+    /// Send a distribution value.
+    ///
+    /// Unlike histograms, distributions are aggregated globally by
+    /// Datadog rather than by each agent.
+    ///
+    /// ```ignore
+    /// // pass response latency value
+    /// client.distribution("response.latency", 12.5, tags);
+    /// ```
+    pub fn distribution(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Distribution));
+        if let Some(aggregator) = self.extended_aggregator() {
+            return aggregator.distribution(metric, value, tags);
+        }
+        let data = self.prepare_with_tags(format!("{}:{}|d", metric, value), tags);
+        self.send(data);
+    }
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:9.1|g", response);
+    /// Send a set value.
+    ///
+    /// Sets count the number of unique values seen for a metric
+    /// over a flush interval.
+    ///
+    /// ```ignore
+    /// // count unique visitors
+    /// client.set("users.unique", user_id, tags);
+    /// ```
+    pub fn set<V: fmt::Display>(&self, metric: &str, value: V, tags: Option<&Vec<&str>>) {
+        if !self.client.check_metric_name(metric) {
+            return;
+        }
+        self.client.record(|t| t.metric(MetricType::Set));
+        if let Some(aggregator) = &self.client.aggregator {
+            return aggregator.set(metric, value.to_string(), tags);
+        }
+        let data = self.prepare_with_tags(format!("{}:{}|s", metric, value), tags);
+        self.send(data);
     }
 
-    #[test]
-    fn test_sending_gauge_without_prefix() {
+    /// Send a event.
+    ///
+    /// ```no_run
+    /// # use datadog_statsd::client::{AlertType, Client, ClientConfig};
+    /// # let config = ClientConfig::builder(("127.0.0.1", 8125)).build();
+    /// # let client = Client::new(&config).unwrap();
+    /// // pass a app start event
+    /// client.event("MyApp Start", "MyApp Details", AlertType::Info, Some(&vec!["tag1", "tag2:test"]));
+    /// ```
+    pub fn event(&self, title: &str, text: &str, alert_type: AlertType, tags: Option<&Vec<&str>>) {
+        self.send_event(&Event::new(title, text).alert_type(alert_type), tags)
+    }
+
+    /// Send a event built with `Event`, for the fields `event` doesn't take.
+    ///
+    /// ```no_run
+    /// # use datadog_statsd::client::{Client, ClientConfig, Event, EventPriority};
+    /// # let config = ClientConfig::builder(("127.0.0.1", 8125)).build();
+    /// # let client = Client::new(&config).unwrap();
+    /// let event = Event::new("MyApp Deploy", "Deployed version 1.2.3")
+    ///     .hostname("web-1")
+    ///     .aggregation_key("deploy")
+    ///     .priority(EventPriority::Low)
+    ///     .source_type_name("jenkins");
+    /// client.send_event(&event, Some(&vec!["tag1", "tag2:test"]));
+    /// ```
+    pub fn send_event(&self, event: &Event, tags: Option<&Vec<&str>>) {
+        self.client.record(|t| t.event());
+        let event_with_tags = self.append_tags(event.format(), tags);
+        self.send(event_with_tags)
+    }
+
+    /// Send a service check.
+    ///
+    /// ```no_run
+    /// # use datadog_statsd::client::{Client, ClientConfig, ServiceCheckStatus};
+    /// # let config = ClientConfig::builder(("127.0.0.1", 8125)).build();
+    /// # let client = Client::new(&config).unwrap();
+    /// // pass a app status
+    /// client.service_check("MyApp", ServiceCheckStatus::Ok, Some(&vec!["tag1", "tag2:test"]));
+    /// ```
+    pub fn service_check(
+        &self,
+        service_check_name: &str,
+        status: ServiceCheckStatus,
+        tags: Option<&Vec<&str>>,
+    ) {
+        self.send_service_check(&ServiceCheck::new(service_check_name, status), tags)
+    }
+
+    /// Send a service check built with `ServiceCheck`, for the fields
+    /// `service_check` doesn't take.
+    ///
+    /// ```no_run
+    /// # use datadog_statsd::client::{Client, ClientConfig, ServiceCheck, ServiceCheckStatus};
+    /// # let config = ClientConfig::builder(("127.0.0.1", 8125)).build();
+    /// # let client = Client::new(&config).unwrap();
+    /// let check = ServiceCheck::new("MyApp", ServiceCheckStatus::Critical)
+    ///     .hostname("web-1")
+    ///     .message("database is unreachable");
+    /// client.send_service_check(&check, Some(&vec!["tag1", "tag2:test"]));
+    /// ```
+    pub fn send_service_check(&self, service_check: &ServiceCheck, tags: Option<&Vec<&str>>) {
+        self.client.record(|t| t.service_check());
+        let sc_with_tags = self.client.format_service_check(service_check, tags);
+        self.send(sc_with_tags)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AlertType {
+    Info,
+    Error,
+    Warning,
+    Success,
+}
+
+impl fmt::Display for AlertType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventPriority {
+    Normal,
+    Low,
+}
+
+impl fmt::Display for EventPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A Datadog event, with the optional fields of the event protocol.
+///
+/// ```
+/// use datadog_statsd::client::{AlertType, Event, EventPriority};
+///
+/// let event = Event::new("Deploy", "Deployed version 1.2.3")
+///     .alert_type(AlertType::Success)
+///     .hostname("web-1")
+///     .aggregation_key("deploy")
+///     .priority(EventPriority::Low)
+///     .source_type_name("jenkins");
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    title: String,
+    text: String,
+    alert_type: AlertType,
+    date_happened: Option<time::SystemTime>,
+    hostname: Option<String>,
+    aggregation_key: Option<String>,
+    priority: Option<EventPriority>,
+    source_type_name: Option<String>,
+}
+
+impl Event {
+    pub fn new(title: &str, text: &str) -> Self {
+        Event {
+            title: title.into(),
+            text: text.into(),
+            alert_type: AlertType::Info,
+            date_happened: None,
+            hostname: None,
+            aggregation_key: None,
+            priority: None,
+            source_type_name: None,
+        }
+    }
+
+    pub fn alert_type(mut self, alert_type: AlertType) -> Self {
+        self.alert_type = alert_type;
+        self
+    }
+
+    /// When the event happened. Defaults to when the agent receives it.
+    pub fn date_happened(mut self, date_happened: time::SystemTime) -> Self {
+        self.date_happened = Some(date_happened);
+        self
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Events with the same aggregation key are grouped together in Datadog.
+    pub fn aggregation_key(mut self, aggregation_key: &str) -> Self {
+        self.aggregation_key = Some(aggregation_key.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: EventPriority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn source_type_name(mut self, source_type_name: &str) -> Self {
+        self.source_type_name = Some(source_type_name.into());
+        self
+    }
+
+    /// Format the event, without tags.
+    ///
+    /// Newlines in the title and text are escaped as `\n`, as the protocol
+    /// requires, and the lengths in the header are those of the escaped strings.
+    pub(crate) fn format(&self) -> String {
+        let title = self.title.replace('\n', "\\n");
+        let text = self.text.replace('\n', "\\n");
+        let mut d = vec![];
+        d.push(format!("_e{{{},{}}}:{}", title.len(), text.len(), title));
+        d.push(text);
+        if let Some(date_happened) = self.date_happened {
+            d.push(format!("d:{}", unix_timestamp(date_happened)));
+        }
+        if let Some(hostname) = &self.hostname {
+            d.push(format!("h:{}", hostname));
+        }
+        if let Some(aggregation_key) = &self.aggregation_key {
+            d.push(format!("k:{}", aggregation_key));
+        }
+        if let Some(priority) = &self.priority {
+            d.push(format!("p:{}", priority.to_string().to_lowercase()));
+        }
+        if let Some(source_type_name) = &self.source_type_name {
+            d.push(format!("s:{}", source_type_name));
+        }
+        if self.alert_type != AlertType::Info {
+            d.push(format!("t:{}", self.alert_type.to_string().to_lowercase()))
+        }
+        d.join("|")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServiceCheckStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+/// A Datadog service check, with the optional fields of the service check
+/// protocol.
+///
+/// ```
+/// use datadog_statsd::client::{ServiceCheck, ServiceCheckStatus};
+///
+/// let check = ServiceCheck::new("myapp.db", ServiceCheckStatus::Critical)
+///     .hostname("web-1")
+///     .message("database is unreachable");
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceCheck {
+    name: String,
+    status: ServiceCheckStatus,
+    timestamp: Option<time::SystemTime>,
+    hostname: Option<String>,
+    message: Option<String>,
+}
+
+impl ServiceCheck {
+    pub fn new(name: &str, status: ServiceCheckStatus) -> Self {
+        ServiceCheck {
+            name: name.into(),
+            status,
+            timestamp: None,
+            hostname: None,
+            message: None,
+        }
+    }
+
+    /// When the check ran. Defaults to when the agent receives it.
+    pub fn timestamp(mut self, timestamp: time::SystemTime) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// A description of the check's status, e.g. why it failed.
+    pub fn message(mut self, message: &str) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Format the service check, without tags or message.
+    pub(crate) fn format(&self) -> String {
+        let mut d = vec![];
+        d.push("_sc".to_string());
+        d.push(self.name.clone());
+        d.push((self.status.clone() as u32).to_string());
+        if let Some(timestamp) = self.timestamp {
+            d.push(format!("d:{}", unix_timestamp(timestamp)));
+        }
+        if let Some(hostname) = &self.hostname {
+            d.push(format!("h:{}", hostname));
+        }
+        d.join("|")
+    }
+
+    /// Format the message field, which goes after the tags.
+    ///
+    /// Newlines are escaped as `\n` and `m:` as `m\:`, as the protocol requires.
+    pub(crate) fn format_message(&self) -> Option<String> {
+        self.message
+            .as_ref()
+            .map(|m| format!("m:{}", m.replace('\n', "\\n").replace("m:", "m\\:")))
+    }
+}
+
+/// A metric, event or service check queued in a pipeline. Tags are applied
+/// when the pipeline is sent, so the client's constant tags and the
+/// pipeline's tags are included.
+enum PipelineStat {
+    Metric {
+        metric: String,
+        metric_type: MetricType,
+        data: String,
+        tags: Vec<String>,
+        timestamp: Option<time::SystemTime>,
+    },
+    Event(Event, Vec<String>),
+    ServiceCheck(ServiceCheck, Vec<String>),
+}
+
+impl PipelineStat {
+    /// Validate and count the stat like the `Client` method that sends it
+    /// would, returning whether it should be sent.
+    fn record(&self, client: &InternalClient) -> bool {
+        match self {
+            PipelineStat::Metric {
+                metric,
+                metric_type,
+                ..
+            } => {
+                if !client.check_metric_name(metric) {
+                    return false;
+                }
+                client.record(|t| t.metric(*metric_type));
+            }
+            PipelineStat::Event(..) => client.record(|t| t.event()),
+            PipelineStat::ServiceCheck(..) => client.record(|t| t.service_check()),
+        }
+        true
+    }
+
+    fn format(&self, client: &InternalClient, pipeline_tags: &[String]) -> String {
+        let own_tags = match self {
+            PipelineStat::Metric { tags, .. } => tags,
+            PipelineStat::Event(_, tags) => tags,
+            PipelineStat::ServiceCheck(_, tags) => tags,
+        };
+        let tags: Vec<&str> = pipeline_tags
+            .iter()
+            .chain(own_tags)
+            .map(|t| t.as_str())
+            .collect();
+        let tags = Some(&tags).filter(|t| !t.is_empty());
+        match self {
+            PipelineStat::Metric {
+                data,
+                timestamp: Some(timestamp),
+                ..
+            } => client.prepare_with_timestamp(data, tags, *timestamp),
+            PipelineStat::Metric { data, .. } => client.prepare_with_tags(data, tags),
+            PipelineStat::Event(event, _) => client.append_tags(event.format(), tags),
+            PipelineStat::ServiceCheck(service_check, _) => {
+                client.format_service_check(service_check, tags)
+            }
+        }
+    }
+}
+
+fn to_owned_tags(tags: Option<&Vec<&str>>) -> Vec<String> {
+    tags.map_or(vec![], |v| v.iter().map(|t| t.to_string()).collect())
+}
+
+pub struct Pipeline {
+    stats: VecDeque<PipelineStat>,
+    max_udp_size: usize,
+    tags: Vec<String>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline {
+            stats: VecDeque::new(),
+            max_udp_size: 512,
+            tags: vec![],
+        }
+    }
+
+    /// Set max UDP packet size
+    ///
+    /// Packets are never made larger than the max packet size of the client
+    /// the pipeline is sent with, so this can only lower it.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.set_max_udp_size(128);
+    /// ```
+    pub fn set_max_udp_size(&mut self, max_udp_size: usize) {
+        self.max_udp_size = max_udp_size;
+    }
+
+    /// Get the max UDP packet size
+    pub fn max_udp_size(&self) -> usize {
+        self.max_udp_size
+    }
+
+    /// Set tags to send with every metric in the pipeline
+    ///
+    /// They are sent after the client's constant tags and before each
+    /// metric's own tags.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.set_tags(vec!["batch:nightly"]);
+    /// ```
+    pub fn set_tags(&mut self, tags: Vec<&str>) {
+        self.tags = tags.iter().map(|t| t.to_string()).collect();
+    }
+
+    /// Increment a metric by 1
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Increment a given metric by 1.
+    /// pipe.incr("metric.completed", None);
+    /// ```
+    ///
+    /// This modifies a counter with an effective sampling
+    /// rate of 1.0.
+    pub fn incr(&mut self, metric: &str, tags: Option<&Vec<&str>>) {
+        self.count(metric, 1.0, tags);
+    }
+
+    /// Decrement a metric by -1
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Decrement a given metric by 1
+    /// pipe.decr("metric.completed", None);
+    /// ```
+    ///
+    /// This modifies a counter with an effective sampling
+    /// rate of 1.0.
+    pub fn decr(&mut self, metric: &str, tags: Option<&Vec<&str>>) {
+        self.count(metric, -1.0, tags);
+    }
+
+    /// Modify a counter by `value`.
+    ///
+    /// Will increment or decrement a counter by `value` with
+    /// a sampling rate of 1.0.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Increment by 12
+    /// pipe.count("metric.completed", 12.0, Some(&vec!["tag1"]));
+    /// ```
+    pub fn count(&mut self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let data = format!("{}:{}|c", metric, value);
+        self.push(metric, MetricType::Count, data, tags, None);
+    }
+
+    /// Modify a counter by `value` at a point in the past.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Record 12 completions an hour ago
+    /// let timestamp = SystemTime::now() - Duration::from_secs(3600);
+    /// pipe.count_with_timestamp("metric.completed", 12.0, timestamp, None);
+    /// ```
+    pub fn count_with_timestamp(
+        &mut self,
+        metric: &str,
+        value: f64,
+        timestamp: time::SystemTime,
+        tags: Option<&Vec<&str>>,
+    ) {
+        let data = format!("{}:{}|c", metric, value);
+        self.push(metric, MetricType::Count, data, tags, Some(timestamp));
+    }
+
+    /// Modify a counter by `value` only x% of the time.
+    ///
+    /// Will increment or decrement a counter by `value` with
+    /// a custom sampling rate.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Increment by 4 50% of the time.
+    /// pipe.sampled_count("metric.completed", 4.0, 0.5, None);
+    /// ```
+    pub fn sampled_count(&mut self, metric: &str, value: f64, rate: f64, tags: Option<&Vec<&str>>) {
+        if rand::random::<f64>() >= rate {
+            return;
+        }
+        let data = format!("{}:{}|c|@{}", metric, value, rate);
+        self.push(metric, MetricType::Count, data, tags, None);
+    }
+
+    /// Set a gauge value.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // set a gauge to 9001
+    /// pipe.gauge("power_level.observed", 9001.0, None);
+    /// ```
+    pub fn gauge(&mut self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let data = format!("{}:{}|g", metric, value);
+        self.push(metric, MetricType::Gauge, data, tags, None);
+    }
+
+    /// Set a gauge value at a point in the past.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Record a gauge of 9001 an hour ago
+    /// let timestamp = SystemTime::now() - Duration::from_secs(3600);
+    /// pipe.gauge_with_timestamp("power_level.observed", 9001.0, timestamp, None);
+    /// ```
+    pub fn gauge_with_timestamp(
+        &mut self,
+        metric: &str,
+        value: f64,
+        timestamp: time::SystemTime,
+        tags: Option<&Vec<&str>>,
+    ) {
+        let data = format!("{}:{}|g", metric, value);
+        self.push(metric, MetricType::Gauge, data, tags, Some(timestamp));
+    }
+
+    /// Send a timer value.
+    ///
+    /// The value is expected to be in ms.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass a duration value
+    /// pipe.timer("response.duration", 10.123, None);
+    /// ```
+    pub fn timer(&mut self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let data = format!("{}:{}|ms", metric, value);
+        self.push(metric, MetricType::Timing, data, tags, None);
+    }
+
+    /// Time a block of code.
+    ///
+    /// The passed closure will be timed and executed. The block's
+    /// duration will be sent as a metric.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass a duration value
+    /// pipe.time("response.duration", None, || {
+    ///   // Your code here.
+    /// });
+    /// ```
+    pub fn time<F>(&mut self, metric: &str, tags: Option<&Vec<&str>>, callable: F)
+    where
+        F: FnOnce(),
+    {
+        let start = time::Instant::now();
+        callable();
+        let used = start.elapsed();
+        let data = format!("{}:{}|ms", metric, timer_value(used));
+        self.push(metric, MetricType::Timing, data, tags, None);
+    }
+
+    /// Send a histogram value.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass response size value
+    /// pipe.histogram("response.size", 128.0, None);
+    /// ```
+    pub fn histogram(&mut self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let data = format!("{}:{}|h", metric, value);
+        self.push(metric, MetricType::Histogram, data, tags, None);
+    }
+
+    /// Send a distribution value.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass response latency value
+    /// pipe.distribution("response.latency", 12.5, None);
+    /// ```
+    pub fn distribution(&mut self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        let data = format!("{}:{}|d", metric, value);
+        self.push(metric, MetricType::Distribution, data, tags, None);
+    }
+
+    /// Send a set value.
+    ///
+    /// ```
+    /// use datadog_statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // count unique visitors
+    /// pipe.set("users.unique", 42, None);
+    /// ```
+    pub fn set<V: fmt::Display>(&mut self, metric: &str, value: V, tags: Option<&Vec<&str>>) {
+        let data = format!("{}:{}|s", metric, value);
+        self.push(metric, MetricType::Set, data, tags, None);
+    }
+
+    /// Send a event.
+    ///
+    /// ```
+    /// use datadog_statsd::client::{AlertType, Pipeline};
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass a app start event
+    /// pipe.event("MyApp Start", "MyApp Details", AlertType::Info, None);
+    /// ```
+    pub fn event(
+        &mut self,
+        title: &str,
+        text: &str,
+        alert_type: AlertType,
+        tags: Option<&Vec<&str>>,
+    ) {
+        self.send_event(&Event::new(title, text).alert_type(alert_type), tags);
+    }
+
+    /// Send a event built with `Event`, for the fields `event` doesn't take.
+    ///
+    /// ```
+    /// use datadog_statsd::client::{Event, Pipeline};
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.send_event(&Event::new("MyApp Deploy", "Deployed").hostname("web-1"), None);
+    /// ```
+    pub fn send_event(&mut self, event: &Event, tags: Option<&Vec<&str>>) {
+        self.stats
+            .push_back(PipelineStat::Event(event.clone(), to_owned_tags(tags)));
+    }
+
+    /// Send a service check.
+    ///
+    /// ```
+    /// use datadog_statsd::client::{Pipeline, ServiceCheckStatus};
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass a app status
+    /// pipe.service_check("MyApp", ServiceCheckStatus::Ok, None);
+    /// ```
+    pub fn service_check(
+        &mut self,
+        service_check_name: &str,
+        status: ServiceCheckStatus,
+        tags: Option<&Vec<&str>>,
+    ) {
+        self.send_service_check(&ServiceCheck::new(service_check_name, status), tags);
+    }
+
+    /// Send a service check built with `ServiceCheck`, for the fields
+    /// `service_check` doesn't take.
+    ///
+    /// ```
+    /// use datadog_statsd::client::{Pipeline, ServiceCheck, ServiceCheckStatus};
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// let check = ServiceCheck::new("MyApp", ServiceCheckStatus::Critical).message("down");
+    /// pipe.send_service_check(&check, None);
+    /// ```
+    pub fn send_service_check(&mut self, service_check: &ServiceCheck, tags: Option<&Vec<&str>>) {
+        self.stats.push_back(PipelineStat::ServiceCheck(
+            service_check.clone(),
+            to_owned_tags(tags),
+        ));
+    }
+
+    fn push(
+        &mut self,
+        metric: &str,
+        metric_type: MetricType,
+        data: String,
+        tags: Option<&Vec<&str>>,
+        timestamp: Option<time::SystemTime>,
+    ) {
+        self.stats.push_back(PipelineStat::Metric {
+            metric: metric.into(),
+            metric_type,
+            data,
+            tags: to_owned_tags(tags),
+            timestamp,
+        });
+    }
+
+    /// Format and empty the pipeline, dropping any stat `client` rejects.
+    pub(crate) fn take_lines(&mut self, client: &InternalClient) -> Vec<String> {
+        let mut lines = Vec::with_capacity(self.stats.len());
+        while let Some(stat) = self.stats.pop_front() {
+            if stat.record(client) {
+                lines.push(stat.format(client, &self.tags));
+            }
+        }
+        lines
+    }
+
+    /// Send data along the client's transport.
+    pub fn send(&mut self, client: &Client) {
+        let max_size = self.max_udp_size.min(client.client.max_packet_size);
+        let mut builder = PacketBuilder::new(max_size);
+        for data in self.take_lines(&client.client) {
+            client.client.pack(&mut builder, data.as_bytes());
+        }
+        if let Some(packet) = builder.finish() {
+            client.send(packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+    use self::rand::distributions::{IndependentSample, Range};
+    use super::*;
+    use std::io;
+    use std::net::UdpSocket;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixDatagram;
+    use std::str;
+    use std::sync::mpsc::sync_channel;
+    use std::sync::Mutex;
+    use std::thread;
+
+    static PORT: u16 = 8125;
+
+    // Generates random ports.
+    // Having random ports helps tests not collide over
+    // shared ports.
+    fn next_test_ip4() -> String {
+        let range = Range::new(0, 1000);
+        let mut rng = rand::thread_rng();
+        let port = PORT + range.ind_sample(&mut rng);
+        format!("127.0.0.1:{}", port)
+    }
+
+    // Makes a udpsocket that acts as a statsd server.
+    fn make_server(host: &str) -> UdpSocket {
+        UdpSocket::bind(host).ok().unwrap()
+    }
+
+    // Makes a `Client` with a prefix.
+    fn make_client(host: &str) -> Client {
+        let config = ClientConfig::builder(host)
+            .prefix("myapp")
+            .telemetry(false)
+            .build();
+        Client::new(&config).unwrap()
+    }
+
+    // Makes a `Client` with no prefix.
+    fn make_client_without_prefix(host: &str) -> Client {
+        let config = ClientConfig::builder(host).telemetry(false).build();
+        Client::new(&config).unwrap()
+    }
+
+    // Makes a client with prefix and constant tags.
+    fn make_client_with_prefix_and_constant_tags(host: &str) -> Client {
+        let config = ClientConfig::builder(host)
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common", "tag2common:test"])
+            .telemetry(false)
+            .build();
+        Client::new(&config).unwrap()
+    }
+
+    // Generates a socket path that is unique to the test.
+    #[cfg(unix)]
+    fn next_test_socket_path(name: &str) -> PathBuf {
+        let range = Range::new(0, 1_000_000);
+        let mut rng = rand::thread_rng();
+        std::env::temp_dir().join(format!(
+            "dsd-{}-{}.socket",
+            name,
+            range.ind_sample(&mut rng)
+        ))
+    }
+
+    fn server_recv(server: UdpSocket) -> String {
+        let (serv_tx, serv_rx) = sync_channel(1);
+        let _t = thread::spawn(move || {
+            let mut buf = [0; 128];
+            let (len, _) = match server.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(_) => panic!("No response from test server."),
+            };
+            drop(server);
+            let bytes = Vec::from(&buf[0..len]);
+            serv_tx.send(bytes).unwrap();
+        });
+
+        let bytes = serv_rx.recv().ok().unwrap();
+        str::from_utf8(&bytes).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_sending_gauge() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        client.gauge("metric", 9.1, None);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|g", response);
+    }
+
+    #[test]
+    fn test_sending_gauge_without_prefix() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client_without_prefix(&host);
+
+        client.gauge("metric", 9.1, None);
+
+        let response = server_recv(server);
+        assert_eq!("metric:9.1|g", response);
+    }
+
+    #[test]
+    fn test_sending_incr() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        client.incr("metric", None);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:1|c", response);
+    }
+
+    #[test]
+    fn test_sending_decr() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        client.decr("metric", None);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:-1|c", response);
+    }
+
+    #[test]
+    fn test_sending_count() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        client.count("metric", 12.2, None);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:12.2|c", response);
+    }
+
+    #[test]
+    fn test_sending_timer() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        client.timer("metric", 21.39, None);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:21.39|ms", response);
+    }
+
+    #[test]
+    fn test_sending_timed_block() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        struct TimeTest {
+            num: u8,
+        }
+
+        let mut t = TimeTest { num: 10 };
+        let output = client.time("time_block", None, || {
+            t.num += 2;
+            "a string"
+        });
+
+        let response = server_recv(server);
+        assert_eq!(output, "a string");
+        assert_eq!(t.num, 12);
+        assert!(response.contains("myapp.time_block"));
+        assert!(response.contains("|ms"));
+    }
+
+    #[test]
+    fn test_sending_durations_in_configured_unit() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .duration_unit(DurationUnit::Nanoseconds)
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        client.time_distribution("time_block", None, || {
+            thread::sleep(time::Duration::from_millis(2))
+        });
+
+        let datagrams = transport.datagrams();
+        let value: f64 = datagrams[0]
+            .strip_prefix("time_block:")
+            .and_then(|d| d.strip_suffix("|d"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(value >= 2_000_000.0);
+
+        // Timers stay in milliseconds.
+        client.time("time_block", None, || {
+            thread::sleep(time::Duration::from_millis(2))
+        });
+        let datagrams = transport.datagrams();
+        let value: f64 = datagrams[1]
+            .strip_prefix("time_block:")
+            .and_then(|d| d.strip_suffix("|ms"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((2.0..1000.0).contains(&value));
+
+        assert_eq!(
+            DurationUnit::Milliseconds.convert(time::Duration::from_micros(1500)),
+            1.5
+        );
+        assert_eq!(
+            DurationUnit::Microseconds.convert(time::Duration::from_micros(1500)),
+            1500.0
+        );
+        assert_eq!(
+            DurationUnit::Seconds.convert(time::Duration::from_millis(250)),
+            0.25
+        );
+    }
+
+    #[test]
+    fn test_time_result_tags_outcomes() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(()).telemetry(false).build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        let ok: Result<u8, ()> = client.time_result("query", Some(&vec!["db:users"]), || Ok(1));
+        assert_eq!(ok, Ok(1));
+        let err: Result<(), &str> = client.time_result("query", None, || Err("timeout"));
+        assert_eq!(err, Err("timeout"));
+
+        assert_eq!(
+            transport.datagrams_without_timings(),
+            vec!["query:_|ms|#db:users,status:ok", "query:_|ms|#status:error"]
+        );
+    }
+
+    #[test]
+    fn test_time_result_counts_errors() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .outcome_tags("outcome:success", "")
+            .error_counter_suffix(".errors")
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        let tags = vec!["db:users"];
+        let _: Result<(), ()> = client.time_result("query", Some(&tags), || Ok(()));
+        let _: Result<(), ()> = client.time_result("query", Some(&tags), || Err(()));
+
+        assert_eq!(
+            transport.datagrams_without_timings(),
+            vec![
+                "query:_|ms|#db:users,outcome:success",
+                "query:_|ms|#db:users",
+                "query.errors:1|c|#db:users",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_time_async_result_tags_outcomes() {
+        use crate::testing::CapturingTransport;
+        use futures::executor::block_on;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .error_counter_suffix(".errors")
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        let err: Result<(), &str> =
+            block_on(client.time_async_result("fetch", None, async { Err("refused") }));
+        assert_eq!(err, Err("refused"));
+
+        assert_eq!(
+            transport.datagrams_without_timings(),
+            vec!["fetch:_|ms|#status:error", "fetch.errors:1|c"]
+        );
+    }
+
+    #[test]
+    fn test_sending_histogram() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        // without tags
+        client.histogram("metric", 9.1, None);
+        let mut response = server_recv(server.try_clone().unwrap());
+        assert_eq!("myapp.metric:9.1|h", response);
+        // with tags
+        client.histogram("metric", 9.1, Some(&vec!["tag1", "tag2:test"]));
+        response = server_recv(server.try_clone().unwrap());
+        assert_eq!("myapp.metric:9.1|h|#tag1,tag2:test", response);
+    }
+
+    #[test]
+    fn test_sending_histogram_with_prefix_and_constant_tags() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client_with_prefix_and_constant_tags(&host);
+
+        // without tags
+        client.histogram("metric", 9.1, None);
+        let mut response = server_recv(server.try_clone().unwrap());
+        assert_eq!("myapp.metric:9.1|h|#tag1common,tag2common:test", response);
+        // with tags
+        let tags = Some(vec!["tag1", "tag2:test"]);
+        client.histogram("metric", 9.1, tags.as_ref());
+        response = server_recv(server.try_clone().unwrap());
+        assert_eq!(
+            "myapp.metric:9.1|h|#tag1common,tag2common:test,tag1,tag2:test",
+            response
+        );
+        // repeat
+        client.histogram("metric", 19.12, tags.as_ref());
+        response = server_recv(server.try_clone().unwrap());
+        assert_eq!(
+            "myapp.metric:19.12|h|#tag1common,tag2common:test,tag1,tag2:test",
+            response
+        );
+    }
+
+    #[test]
+    fn test_sending_distribution() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        client.distribution("metric", 9.1, Some(&vec!["tag1"]));
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|d|#tag1", response);
+    }
+
+    #[test]
+    fn test_sending_time_distribution() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        let output = client.time_distribution("time_block", None, || "a string");
+
+        let response = server_recv(server);
+        assert_eq!(output, "a string");
+        assert!(response.starts_with("myapp.time_block:"));
+        assert!(response.ends_with("|d"));
+    }
+
+    #[test]
+    fn test_sending_time_distribution_async() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+
+        let output = futures::executor::block_on(client.time_distribution_async(
+            "time_block",
+            None,
+            async { "a string" },
+        ));
+
+        let response = server_recv(server);
+        assert_eq!(output, "a string");
+        assert!(response.starts_with("myapp.time_block:"));
+        assert!(response.ends_with("|d"));
+    }
+
+    #[test]
+    fn test_sending_set() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client_with_prefix_and_constant_tags(&host);
+
+        // without tags
+        client.set("users", "user-123", None);
+        let mut response = server_recv(server.try_clone().unwrap());
+        assert_eq!(
+            "myapp.users:user-123|s|#tag1common,tag2common:test",
+            response
+        );
+        // with tags
+        client.set("users", 42, Some(&vec!["tag1"]));
+        response = server_recv(server.try_clone().unwrap());
+        assert_eq!(
+            "myapp.users:42|s|#tag1common,tag2common:test,tag1",
+            response
+        );
+    }
+
+    #[test]
+    fn test_sending_event_with_tags() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let tags = Some(vec!["tag1", "tag2:test"]);
+
+        client.event("Title Test", "Text ABC", AlertType::Error, tags.as_ref());
+
+        let response = server_recv(server.try_clone().unwrap());
+        assert_eq!(
+            "_e{10,8}:Title Test|Text ABC|t:error|#tag1,tag2:test",
+            response
+        );
+
+        client.event(
+            "Title Test 2",
+            "Text ABCD",
+            AlertType::Warning,
+            tags.as_ref(),
+        );
+        let response = server_recv(server.try_clone().unwrap());
+        assert_eq!(
+            "_e{12,9}:Title Test 2|Text ABCD|t:warning|#tag1,tag2:test",
+            response
+        );
+    }
+
+    #[test]
+    fn test_sending_event_with_all_fields() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(()).telemetry(false).build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        let event = Event::new("Deploy", "Deployed\nversion 1.2.3")
+            .alert_type(AlertType::Success)
+            .date_happened(time::UNIX_EPOCH + time::Duration::from_secs(1_656_581_400))
+            .hostname("web-1")
+            .aggregation_key("deploy")
+            .priority(EventPriority::Low)
+            .source_type_name("jenkins");
+        client.send_event(&event, Some(&vec!["tag1"]));
+        client.event("Title\nTest", "Text", AlertType::Info, None);
+
+        assert_eq!(
+            transport.datagrams(),
+            vec![
+                "_e{6,23}:Deploy|Deployed\\nversion 1.2.3|d:1656581400|h:web-1|k:deploy|p:low|s:jenkins|t:success|#tag1",
+                "_e{11,4}:Title\\nTest|Text",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sending_service_check_with_tags() {
         let host = next_test_ip4();
         let server = make_server(&host);
-        let client = make_client_without_prefix(&host);
+        let client = make_client(&host);
+
+        client.service_check(
+            "Service.check.name",
+            ServiceCheckStatus::Critical,
+            Some(&vec!["tag1", "tag2:test"]),
+        );
+
+        let response = server_recv(server);
+        assert_eq!("_sc|Service.check.name|2|#tag1,tag2:test", response);
+    }
+
+    #[test]
+    fn test_sending_service_check_with_all_fields() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .constant_tags(vec!["tag1common"])
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        let check = ServiceCheck::new("myapp.db", ServiceCheckStatus::Critical)
+            .timestamp(time::UNIX_EPOCH + time::Duration::from_secs(1_656_581_400))
+            .hostname("web-1")
+            .message("connection refused\nm: retrying");
+        client.send_service_check(&check, Some(&vec!["tag1"]));
+        client.send_service_check(
+            &ServiceCheck::new("myapp.db", ServiceCheckStatus::Ok).message("ok"),
+            None,
+        );
+
+        assert_eq!(
+            transport.datagrams(),
+            vec![
+                "_sc|myapp.db|2|d:1656581400|h:web-1|#tag1common,tag1|m:connection refused\\nm\\: retrying",
+                "_sc|myapp.db|0|#tag1common|m:ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_sending_time_block() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("metric", 9.1, None);
+        struct TimeTest {
+            num: u8,
+        }
+
+        let mut t = TimeTest { num: 10 };
+        pipeline.time("time_block", None, || {
+            t.num += 2;
+        });
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!(t.num, 12);
+        let (gauge, timer) = response.split_once('\n').unwrap();
+        assert_eq!("myapp.metric:9.1|g", gauge);
+        // Sub-millisecond durations are sent as fractions rather than 0.
+        let value: f64 = timer
+            .strip_prefix("myapp.time_block:")
+            .and_then(|t| t.strip_suffix("|ms"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(value > 0.0 && value < 1000.0);
+    }
+
+    #[test]
+    fn test_pipeline_sending_gauge() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|g", response);
+    }
+
+    #[test]
+    fn test_pipeline_sending_histogram() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.histogram("metric", 9.1, None);
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|h", response);
+    }
+
+    #[test]
+    fn test_pipeline_sending_distribution() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.distribution("metric", 9.1, None);
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|d", response);
+    }
+
+    #[test]
+    fn test_pipeline_sending_set() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.set("users", "user-123", None);
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.users:user-123|s", response);
+    }
+
+    #[test]
+    fn test_pipeline_sending_multiple_data() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.count("metric", 12.2, None);
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|g\nmyapp.metric:12.2|c", response);
+    }
+
+    #[test]
+    fn test_pipeline_set_max_udp_size() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+        pipeline.set_max_udp_size(20);
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.count("metric", 12.2, None);
+        pipeline.send(&client);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.metric:9.1|g", response);
+    }
+
+    #[test]
+    fn test_pipeline_send_metric_after_pipeline() {
+        let host = next_test_ip4();
+        let server = make_server(&host);
+        let client = make_client(&host);
+        let mut pipeline = client.pipeline();
+
+        pipeline.gauge("load", 9.0, None);
+        pipeline.count("customers", 7.0, None);
+        pipeline.send(&client);
+
+        // Should still be able to send metrics
+        // with the client.
+        client.count("customers", 6.0, None);
+
+        let response = server_recv(server);
+        assert_eq!("myapp.load:9|g\nmyapp.customers:7|c", response);
+    }
+
+    #[test]
+    fn test_pipeline_sending_tags() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common"])
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("load", 9.0, Some(&vec!["tag1"]));
+        pipeline.set_tags(vec!["batch:nightly"]);
+        pipeline.count("customers", 7.0, None);
+        pipeline.histogram("size", 3.0, Some(&vec!["tag1", "tag2:test"]));
+        pipeline.send(&client);
+
+        // Pipeline tags apply to every metric, since tags are added at send time.
+        assert_eq!(
+            transport.lines(),
+            vec![
+                "myapp.load:9|g|#tag1common,batch:nightly,tag1",
+                "myapp.customers:7|c|#tag1common,batch:nightly",
+                "myapp.size:3|h|#tag1common,batch:nightly,tag1,tag2:test",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_sending_events_and_service_checks() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common"])
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+        let mut pipeline = client.pipeline();
+        pipeline.incr("deploys", None);
+        pipeline.event(
+            "Deploy",
+            "Text\nABC",
+            AlertType::Success,
+            Some(&vec!["tag1"]),
+        );
+        pipeline.service_check("myapp.up", ServiceCheckStatus::Ok, None);
+        pipeline.send_service_check(
+            &ServiceCheck::new("myapp.db", ServiceCheckStatus::Critical).message("down"),
+            Some(&vec!["tag1"]),
+        );
+        pipeline.send(&client);
+
+        assert_eq!(
+            transport.datagrams(),
+            vec![[
+                "myapp.deploys:1|c|#tag1common",
+                "_e{6,9}:Deploy|Text\\nABC|t:success|#tag1common,tag1",
+                "_sc|myapp.up|0|#tag1common",
+                "_sc|myapp.db|2|#tag1common,tag1|m:down",
+            ]
+            .join("\n")]
+        );
+    }
+
+    #[test]
+    fn test_statsd_addr_parsing() {
+        assert_eq!(
+            "127.0.0.1:8125".to_statsd_addr().unwrap(),
+            StatsdAddr::Udp("127.0.0.1:8125".parse().unwrap())
+        );
+        assert_eq!(
+            "udp://127.0.0.1:8125".parse::<StatsdAddr>().unwrap(),
+            StatsdAddr::Udp("127.0.0.1:8125".parse().unwrap())
+        );
+        assert_eq!(
+            ("127.0.0.1", 8125).to_statsd_addr().unwrap(),
+            StatsdAddr::Udp("127.0.0.1:8125".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:///var/run/datadog/dsd.socket"
+                .parse::<StatsdAddr>()
+                .unwrap(),
+            StatsdAddr::UnixDatagram(PathBuf::from("/var/run/datadog/dsd.socket"))
+        );
+        assert_eq!(
+            "unixgram:///var/run/datadog/dsd.socket"
+                .parse::<StatsdAddr>()
+                .unwrap(),
+            StatsdAddr::UnixDatagram(PathBuf::from("/var/run/datadog/dsd.socket"))
+        );
+        assert_eq!(
+            "unixstream:///var/run/datadog/dsd.socket"
+                .parse::<StatsdAddr>()
+                .unwrap(),
+            StatsdAddr::UnixStream(PathBuf::from("/var/run/datadog/dsd.socket"))
+        );
+    }
+
+    #[test]
+    fn test_unparsed_scheme_addresses_say_to_parse() {
+        let error = "unix:///var/run/datadog/dsd.socket"
+            .to_statsd_addr()
+            .unwrap_err();
+        assert!(matches!(error, StatsdError::AddrParseError(_)));
+        assert!(error.to_string().contains(".parse::<StatsdAddr>()"));
+
+        let config = ClientConfig::builder("unixstream:///var/run/datadog/dsd.socket")
+            .telemetry(false)
+            .build();
+        match Client::new(&config) {
+            Err(e) => assert!(e.to_string().contains(".parse::<StatsdAddr>()")),
+            Ok(_) => panic!("expected an unparsed unixstream:// address to fail"),
+        }
+    }
+
+    #[test]
+    fn test_generic_over_to_socket_addrs() {
+        fn make_client<A: ToSocketAddrs>(address: A) -> Client {
+            Client::new(&ClientConfig::builder(address).telemetry(false).build()).unwrap()
+        }
+        make_client(next_test_ip4());
+        make_client(("127.0.0.1", 8125));
+    }
+
+    #[test]
+    fn test_pipeline_max_udp_size_from_client() {
+        let client = make_client(&next_test_ip4());
+        assert_eq!(client.pipeline().max_udp_size, DEFAULT_UDP_PACKET_SIZE);
+
+        let config = ClientConfig::builder(next_test_ip4())
+            .max_packet_size(1432)
+            .telemetry(false)
+            .build();
+        let client = Client::new(&config).unwrap();
+        assert_eq!(client.pipeline().max_udp_size, 1432);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sending_gauge_over_unix_datagram() {
+        let path = next_test_socket_path("gram");
+        let server = UnixDatagram::bind(&path).unwrap();
+        let config = ClientConfig::builder(
+            format!("unix://{}", path.display())
+                .parse::<StatsdAddr>()
+                .unwrap(),
+        )
+        .prefix("myapp")
+        .telemetry(false)
+        .build();
+        let client = Client::new(&config).unwrap();
+        assert_eq!(client.pipeline().max_udp_size, DEFAULT_UDS_PACKET_SIZE);
+
+        client.gauge("metric", 9.1, None);
+
+        let mut buf = [0; 128];
+        let len = server.recv(&mut buf).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!("myapp.metric:9.1|g", str::from_utf8(&buf[..len]).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sending_gauge_over_unix_stream() {
+        use std::io::Read;
+        use std::os::unix::net::UnixListener;
+
+        let path = next_test_socket_path("stream");
+        let listener = UnixListener::bind(&path).unwrap();
+        let config = ClientConfig::builder(
+            format!("unixstream://{}", path.display())
+                .parse::<StatsdAddr>()
+                .unwrap(),
+        )
+        .prefix("myapp")
+        .telemetry(false)
+        .build();
+        let client = Client::new(&config).unwrap();
+
+        client.gauge("metric", 9.1, None);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!("myapp.metric:9.1|g", str::from_utf8(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_sending_with_transport() {
+        #[derive(Default)]
+        struct VecTransport(Mutex<Vec<String>>);
+
+        impl Transport for VecTransport {
+            fn send(&self, data: &[u8]) -> io::Result<usize> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(str::from_utf8(data).unwrap().to_string());
+                Ok(data.len())
+            }
+
+            fn max_packet_size(&self) -> usize {
+                64
+            }
+        }
+
+        let transport = Arc::new(VecTransport::default());
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(Arc::clone(&transport), &config);
+        assert_eq!(client.pipeline().max_udp_size, 64);
+
+        client.gauge("metric", 9.1, None);
+        client.incr("metric", Some(&vec!["tag1"]));
+
+        assert_eq!(
+            *transport.0.lock().unwrap(),
+            vec!["myapp.metric:9.1|g", "myapp.metric:1|c|#tag1"]
+        );
+    }
+
+    #[test]
+    fn test_sending_buffered() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .max_packet_size(40)
+            .buffered(true)
+            .buffer_flush_interval(time::Duration::from_secs(60))
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
 
         client.gauge("metric", 9.1, None);
+        client.count("metric", 12.2, None);
+        client.incr("other", None);
+        client.flush();
+        assert_eq!(
+            transport.take(),
+            vec!["myapp.metric:9.1|g\nmyapp.metric:12.2|c", "myapp.other:1|c"]
+        );
+
+        // Dropping the last clone flushes whatever is still queued.
+        let clone = client.clone();
+        client.incr("other", None);
+        drop(client);
+        assert!(transport.datagrams().is_empty());
+        drop(clone);
+        assert_eq!(transport.take(), vec!["myapp.other:1|c"]);
+    }
+
+    #[test]
+    fn test_sending_aggregated() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common"])
+            .aggregation(true)
+            .aggregation_flush_interval(time::Duration::from_secs(60))
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        client.incr("metric", None);
+        client.count("metric", 2.0, None);
+        client.gauge("load", 1.0, Some(&vec!["tag1"]));
+        client.gauge("load", 2.0, Some(&vec!["tag1"]));
+        client.set("users", "user-123", None);
+        client.set("users", "user-123", None);
+        // Other metric types are sent right away.
+        client.timer("metric", 1.0, None);
+        assert_eq!(transport.take(), vec!["myapp.metric:1|ms|#tag1common"]);
+
+        client.flush();
+        let datagrams = transport.take();
+        assert_eq!(datagrams.len(), 1);
+        let mut lines: Vec<_> = datagrams[0].split('\n').collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            vec![
+                "myapp.load:2|g|#tag1common,tag1",
+                "myapp.metric:3|c|#tag1common",
+                "myapp.users:user-123|s|#tag1common",
+            ]
+        );
 
-        let response = server_recv(server);
-        assert_eq!("metric:9.1|g", response);
+        // Dropping the client sends what's left.
+        client.incr("metric", None);
+        drop(client);
+        assert_eq!(transport.take(), vec!["myapp.metric:1|c|#tag1common"]);
     }
 
     #[test]
-    fn test_sending_incr() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+    fn test_sending_aggregated_on_interval() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .aggregation(true)
+            .aggregation_flush_interval(time::Duration::from_millis(10))
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
 
         client.incr("metric", None);
-
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:1|c", response);
+        client.incr("metric", None);
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while transport.datagrams().is_empty() && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        assert_eq!(transport.datagrams(), vec!["metric:2|c"]);
     }
 
     #[test]
-    fn test_sending_decr() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-
-        client.decr("metric", None);
+    fn test_sending_extended_aggregated() {
+        use crate::testing::CapturingTransport;
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:-1|c", response);
-    }
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .max_packet_size(40)
+            .extended_aggregation(true)
+            .aggregation_flush_interval(time::Duration::from_secs(60))
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
 
-    #[test]
-    fn test_sending_count() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+        client.incr("metric", None);
+        client.incr("metric", None);
+        client.distribution("latency", 1.5, Some(&vec!["tag1"]));
+        client.distribution("latency", 2.5, Some(&vec!["tag1"]));
+        for i in 0..10 {
+            client.histogram("size", 1000.0 + i as f64, None);
+        }
+        assert!(transport.datagrams().is_empty());
 
-        client.count("metric", 12.2, None);
+        client.flush();
+        let mut lines = transport.lines();
+        lines.sort_unstable();
+        // Multi-value lines are split to fit within the max packet size.
+        assert_eq!(
+            lines,
+            vec![
+                "myapp.latency:1.5:2.5|d|#tag1",
+                "myapp.metric:2|c",
+                "myapp.size:1000:1001:1002:1003:1004|h",
+                "myapp.size:1005:1006:1007:1008:1009|h",
+            ]
+        );
+        assert!(transport.datagrams().iter().all(|d| d.len() <= 40));
+    }
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:12.2|c", response);
+    // Makes a client that captures what it sends and the errors it reports.
+    fn make_client_with_error_handler(
+        max_packet_size: usize,
+    ) -> (
+        Client,
+        crate::testing::CapturingTransport,
+        Arc<Mutex<Vec<String>>>,
+    ) {
+        let transport = crate::testing::CapturingTransport::new();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .max_packet_size(max_packet_size)
+            .error_handler(move |e| errors_clone.lock().unwrap().push(e.to_string()))
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+        (client, transport, errors)
     }
 
     #[test]
-    fn test_sending_timer() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+    fn test_reporting_invalid_metric_names() {
+        let (client, transport, errors) = make_client_with_error_handler(512);
 
-        client.timer("metric", 21.39, None);
+        client.incr("", None);
+        client.gauge("bad:name", 1.0, None);
+        client.set("bad|name", 1, None);
+        client.timer("bad\nname", 1.0, None);
+        client.incr("good.name", None);
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:21.39|ms", response);
+        assert_eq!(transport.datagrams(), vec!["myapp.good.name:1|c"]);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![
+                "invalid metric name: \"\"",
+                "invalid metric name: \"bad:name\"",
+                "invalid metric name: \"bad|name\"",
+                "invalid metric name: \"bad\\nname\"",
+            ]
+        );
     }
 
     #[test]
-    fn test_sending_timed_block() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        struct TimeTest {
-            num: u8,
-        }
+    fn test_reporting_oversized_payloads() {
+        use crate::testing::CapturingTransport;
 
-        let mut t = TimeTest { num: 10 };
-        let output = client.time("time_block", None, || {
-            t.num += 2;
-            "a string"
-        });
+        let transport = CapturingTransport::new();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .max_packet_size(20)
+            .telemetry(true)
+            .telemetry_interval(time::Duration::from_secs(3600))
+            .error_handler(move |e| errors_clone.lock().unwrap().push(e.to_string()))
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
 
-        let response = server_recv(server);
-        assert_eq!(output, "a string");
-        assert_eq!(t.num, 12);
-        assert!(response.contains("myapp.time_block"));
-        assert!(response.contains("|ms"));
+        client.gauge("metric", 9.1, None);
+        client.gauge("metric", 9.1, Some(&vec!["some_long_tag"]));
+
+        assert_eq!(transport.datagrams(), vec!["myapp.metric:9.1|g"]);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec!["payload of 33 bytes exceeds the max packet size of 20 bytes"]
+        );
+        let lines = client.client.telemetry.as_ref().unwrap().flush();
+        for expected in &[
+            "datadog.dogstatsd.client.packets_dropped:1|c",
+            "datadog.dogstatsd.client.bytes_dropped:33|c",
+            "datadog.dogstatsd.client.packets_sent:1|c",
+        ] {
+            assert!(lines.iter().any(|(l, _)| l == expected), "{}", expected);
+        }
     }
 
     #[test]
-    fn test_sending_histogram() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+    fn test_pipeline_packs_within_client_max_packet_size() {
+        use crate::testing::CapturingTransport;
 
-        // without tags
-        client.histogram("metric", 9.1, None);
-        let mut response = server_recv(server.try_clone().unwrap());
-        assert_eq!("myapp.metric:9.1|h", response);
-        // with tags
-        client.histogram("metric", 9.1, Some(&vec!["tag1", "tag2:test"]));
-        response = server_recv(server.try_clone().unwrap());
-        assert_eq!("myapp.metric:9.1|h|#tag1,tag2:test", response);
+        let transport = CapturingTransport::new();
+        let client = Client::with_transport(
+            transport.clone(),
+            &ClientConfig::builder(()).telemetry(false).build(),
+        );
+
+        let mut pipeline = client.pipeline();
+        pipeline.set_max_udp_size(DEFAULT_UDP_PACKET_SIZE * 4);
+        for i in 0..100 {
+            pipeline.gauge("some.long.metric.name", i as f64, None);
+        }
+        pipeline.send(&client);
+
+        let datagrams = transport.datagrams();
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= DEFAULT_UDP_PACKET_SIZE));
+        assert_eq!(transport.lines().len(), 100);
     }
 
     #[test]
-    fn test_sending_histogram_with_prefix_and_constant_tags() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client_with_prefix_and_constant_tags(&host);
+    fn test_pipeline_validates_and_counts_metrics() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        let config = ClientConfig::builder(())
+            .telemetry(true)
+            .telemetry_interval(time::Duration::from_secs(3600))
+            .error_handler(move |e| errors_clone.lock().unwrap().push(e.to_string()))
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        let mut pipeline = client.pipeline();
+        pipeline.incr("bad|name", None);
+        pipeline.incr("good.name", None);
+        pipeline.gauge("good.name", 1.0, None);
+        pipeline.event("Title", "Text", AlertType::Info, None);
+        pipeline.send(&client);
 
-        // without tags
-        client.histogram("metric", 9.1, None);
-        let mut response = server_recv(server.try_clone().unwrap());
-        assert_eq!("myapp.metric:9.1|h|#tag1common,tag2common:test", response);
-        // with tags
-        let tags = Some(vec!["tag1", "tag2:test"]);
-        client.histogram("metric", 9.1, tags.as_ref());
-        response = server_recv(server.try_clone().unwrap());
         assert_eq!(
-            "myapp.metric:9.1|h|#tag1common,tag2common:test,tag1,tag2:test",
-            response
+            transport.datagrams(),
+            vec!["good.name:1|c\ngood.name:1|g\n_e{5,4}:Title|Text"]
         );
-        // repeat
-        client.histogram("metric", 19.12, tags.as_ref());
-        response = server_recv(server.try_clone().unwrap());
         assert_eq!(
-            "myapp.metric:19.12|h|#tag1common,tag2common:test,tag1,tag2:test",
-            response
+            *errors.lock().unwrap(),
+            vec!["invalid metric name: \"bad|name\""]
         );
+        let lines = client.client.telemetry.as_ref().unwrap().flush();
+        for expected in &[
+            "datadog.dogstatsd.client.metrics:2|c",
+            "datadog.dogstatsd.client.events:1|c",
+        ] {
+            assert!(lines.iter().any(|(l, _)| l == expected), "{}", expected);
+        }
     }
 
     #[test]
-    fn test_sending_event_with_tags() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        let tags = Some(vec!["tag1", "tag2:test"]);
-
-        client.event("Title Test", "Text ABC", AlertType::Error, tags.as_ref());
+    fn test_pipeline_drops_oversized_stats() {
+        let (client, transport, errors) = make_client_with_error_handler(512);
+        let mut pipeline = client.pipeline();
+        pipeline.set_max_udp_size(20);
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.gauge("metric", 9.1, Some(&vec!["some_long_tag"]));
+        pipeline.count("metric", 12.2, None);
+        pipeline.send(&client);
 
-        let response = server_recv(server.try_clone().unwrap());
+        // The pipeline's size applies even though the client's is larger.
         assert_eq!(
-            "_e{10,8}:Title Test|Text ABC|t:error|#tag1,tag2:test",
-            response
-        );
-
-        client.event(
-            "Title Test 2",
-            "Text ABCD",
-            AlertType::Warning,
-            tags.as_ref(),
+            transport.datagrams(),
+            vec!["myapp.metric:9.1|g", "myapp.metric:12.2|c"]
         );
-        let response = server_recv(server.try_clone().unwrap());
         assert_eq!(
-            "_e{12,9}:Title Test 2|Text ABCD|t:warning|#tag1,tag2:test",
-            response
+            *errors.lock().unwrap(),
+            vec!["payload of 33 bytes exceeds the max packet size of 20 bytes"]
         );
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_sending_service_check_with_tags() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+    fn test_reporting_io_errors() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+        // Unix datagrams to a path nobody is listening on fail to send.
+        let address = format!("unix://{}", next_test_socket_path("missing").display());
+        let config = ClientConfig::builder(address.parse::<StatsdAddr>().unwrap())
+            .error_handler(move |e| errors_clone.lock().unwrap().push(e.to_string()))
+            .telemetry(false)
+            .build();
+        let client = Client::new(&config).unwrap();
 
-        client.service_check(
-            "Service.check.name",
-            ServiceCheckStatus::Critical,
-            Some(&vec!["tag1", "tag2:test"]),
-        );
+        client.incr("metric", None);
 
-        let response = server_recv(server);
-        assert_eq!("_sc|Service.check.name|2|#tag1,tag2:test", response);
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("io error:"));
     }
 
     #[test]
-    fn test_pipeline_sending_time_block() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        let mut pipeline = client.pipeline();
-        pipeline.gauge("metric", 9.1);
-        struct TimeTest {
-            num: u8,
-        }
+    fn test_sending_telemetry() {
+        use crate::testing::CapturingTransport;
 
-        let mut t = TimeTest { num: 10 };
-        pipeline.time("time_block", || {
-            t.num += 2;
-        });
-        pipeline.send(&client);
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common"])
+            .max_packet_size(8192)
+            .telemetry(true)
+            .telemetry_interval(time::Duration::from_secs(3600))
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
 
-        let response = server_recv(server);
-        assert_eq!(t.num, 12);
-        assert_eq!("myapp.metric:9.1|g\nmyapp.time_block:0|ms", response);
+        client.incr("metric", None);
+        client.incr("metric", None);
+        client.gauge("metric", 1.0, None);
+        client.event("Title", "Text", AlertType::Info, None);
+        client.client.flush_telemetry();
+
+        let tags = format!(
+            "#tag1common,client:rust,client_version:{},client_transport:custom",
+            env!("CARGO_PKG_VERSION")
+        );
+        let lines = transport.lines();
+        // Telemetry is neither prefixed nor tagged with anything but the constant tags.
+        for expected in &[
+            format!("datadog.dogstatsd.client.metrics:3|c|{}", tags),
+            format!(
+                "datadog.dogstatsd.client.metrics_by_type:2|c|{},metrics_type:count",
+                tags
+            ),
+            format!("datadog.dogstatsd.client.events:1|c|{}", tags),
+            format!("datadog.dogstatsd.client.packets_sent:4|c|{}", tags),
+        ] {
+            assert!(lines.contains(expected), "{} not in {:#?}", expected, lines);
+        }
     }
 
     #[test]
-    fn test_pipeline_sending_gauge() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        let mut pipeline = client.pipeline();
-        pipeline.gauge("metric", 9.1);
-        pipeline.send(&client);
+    fn test_counting_buffered_packets_where_written() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .buffered(true)
+            .buffer_flush_interval(time::Duration::from_secs(3600))
+            .telemetry(true)
+            .telemetry_interval(time::Duration::from_secs(3600))
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:9.1|g", response);
+        client.incr("metric", None);
+        client.incr("metric", None);
+        client.flush();
+
+        // Two metrics were queued, but only one packet was written.
+        assert_eq!(transport.datagrams(), vec!["metric:1|c\nmetric:1|c"]);
+        let lines = client.client.telemetry.as_ref().unwrap().flush();
+        for expected in &[
+            "datadog.dogstatsd.client.packets_sent:1|c",
+            "datadog.dogstatsd.client.bytes_sent:21|c",
+            "datadog.dogstatsd.client.packets_dropped:0|c",
+        ] {
+            assert!(lines.iter().any(|(l, _)| l == expected), "{}", expected);
+        }
     }
 
     #[test]
-    fn test_pipeline_sending_histogram() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        let mut pipeline = client.pipeline();
-        pipeline.histogram("metric", 9.1);
-        pipeline.send(&client);
+    fn test_sending_without_telemetry() {
+        use crate::testing::CapturingTransport;
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:9.1|h", response);
+        // Telemetry is on unless turned off.
+        assert!(ClientConfig::builder(()).build().telemetry);
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(()).telemetry(false).build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        client.incr("metric", None);
+        client.client.flush_telemetry();
+        client.flush();
+        assert!(client.client.telemetry.is_none());
+        assert_eq!(transport.datagrams(), vec!["metric:1|c"]);
     }
 
     #[test]
-    fn test_pipeline_sending_multiple_data() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        let mut pipeline = client.pipeline();
-        pipeline.gauge("metric", 9.1);
-        pipeline.count("metric", 12.2);
-        pipeline.send(&client);
+    fn test_config_from_env() {
+        use std::collections::HashMap;
+
+        fn from_env(vars: &[(&str, &str)]) -> Result<ClientConfig<StatsdAddr>, StatsdError> {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            ClientConfig::from_env_vars(|key| vars.get(key).cloned())
+        }
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:9.1|g\nmyapp.metric:12.2|c", response);
+        let config = from_env(&[]).unwrap();
+        match config.address {
+            StatsdAddr::Udp(addr) => {
+                assert!(addr.ip().is_loopback());
+                assert_eq!(addr.port(), 8125);
+            }
+            other => panic!("expected a UDP address, got {:?}", other),
+        }
+        assert_eq!(config.constant_tags, None);
+
+        let config =
+            from_env(&[("DD_AGENT_HOST", "10.0.0.1"), ("DD_DOGSTATSD_PORT", "8126")]).unwrap();
+        assert_eq!(
+            config.address,
+            StatsdAddr::Udp("10.0.0.1:8126".parse().unwrap())
+        );
+
+        let config = from_env(&[("DD_AGENT_HOST", "::1")]).unwrap();
+        assert_eq!(
+            config.address,
+            StatsdAddr::Udp("[::1]:8125".parse().unwrap())
+        );
+
+        let config = from_env(&[("DD_AGENT_HOST", "unix:///var/run/datadog/dsd.socket")]).unwrap();
+        assert_eq!(
+            config.address,
+            StatsdAddr::UnixDatagram(PathBuf::from("/var/run/datadog/dsd.socket"))
+        );
+
+        let config = from_env(&[
+            (
+                "DD_DOGSTATSD_URL",
+                "unixstream:///var/run/datadog/dsd.socket",
+            ),
+            ("DD_AGENT_HOST", "10.0.0.1"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.address,
+            StatsdAddr::UnixStream(PathBuf::from("/var/run/datadog/dsd.socket"))
+        );
+
+        assert!(from_env(&[("DD_DOGSTATSD_PORT", "not-a-port")]).is_err());
+
+        let config = from_env(&[
+            ("DD_ENV", "prod"),
+            ("DD_SERVICE", "web"),
+            ("DD_VERSION", ""),
+            ("DD_ENTITY_ID", "1234-abcd"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.constant_tags,
+            Some(vec![
+                "env:prod".to_string(),
+                "service:web".to_string(),
+                "dd.internal.entity_id:1234-abcd".to_string(),
+            ])
+        );
     }
 
     #[test]
-    fn test_pipeline_set_max_udp_size() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
+    fn test_sending_container_id() {
+        use crate::testing::CapturingTransport;
+
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .container_id("abc123")
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+
+        client.incr("metric", None);
+        client.gauge("metric", 9.1, Some(&vec!["tag1"]));
+        client.service_check("check", ServiceCheckStatus::Ok, None);
         let mut pipeline = client.pipeline();
-        pipeline.set_max_udp_size(20);
-        pipeline.gauge("metric", 9.1);
-        pipeline.count("metric", 12.2);
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.count("metric", 12.2, None);
         pipeline.send(&client);
 
-        let response = server_recv(server);
-        assert_eq!("myapp.metric:9.1|g", response);
+        assert_eq!(
+            transport.datagrams(),
+            vec![
+                "myapp.metric:1|c|c:abc123",
+                "myapp.metric:9.1|g|#tag1|c:abc123",
+                "_sc|check|0|c:abc123",
+                "myapp.metric:9.1|g|c:abc123\nmyapp.metric:12.2|c|c:abc123",
+            ]
+        );
     }
 
     #[test]
-    fn test_pipeline_send_metric_after_pipeline() {
-        let host = next_test_ip4();
-        let server = make_server(&host);
-        let client = make_client(&host);
-        let mut pipeline = client.pipeline();
+    fn test_sending_timestamped_metrics() {
+        use crate::testing::CapturingTransport;
 
-        pipeline.gauge("load", 9.0);
-        pipeline.count("customers", 7.0);
-        pipeline.send(&client);
+        let transport = CapturingTransport::new();
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .aggregation(true)
+            .container_id("abc123")
+            .telemetry(false)
+            .build();
+        let client = Client::with_transport(transport.clone(), &config);
+        let timestamp = time::UNIX_EPOCH + time::Duration::from_secs(1_656_581_400);
 
-        // Should still be able to send metrics
-        // with the client.
-        client.count("customers", 6.0, None);
+        client.gauge_with_timestamp("metric", 9.1, timestamp, Some(&vec!["tag1"]));
+        client.count_with_timestamp("metric", 12.0, timestamp, None);
+        let mut pipeline = client.pipeline();
+        pipeline.gauge_with_timestamp("metric", 9.1, timestamp, None);
+        pipeline.count_with_timestamp("metric", 12.0, timestamp, None);
+        pipeline.send(&client);
 
-        let response = server_recv(server);
-        assert_eq!("myapp.load:9|g\nmyapp.customers:7|c", response);
+        // Timestamped points are sent right away, even by an aggregating client.
+        assert_eq!(
+            transport.datagrams(),
+            vec![
+                "myapp.metric:9.1|g|#tag1|T1656581400|c:abc123",
+                "myapp.metric:12|c|T1656581400|c:abc123",
+                "myapp.metric:9.1|g|T1656581400|c:abc123\nmyapp.metric:12|c|T1656581400|c:abc123",
+            ]
+        );
     }
 }
@@ -0,0 +1,242 @@
+//! Extension traits that instrument futures and streams with a `Client`.
+//!
+//! `FutureExt::timed` is like `Client::time_async`, but works on any future
+//! without wrapping it in an `async` block, and also reports how long the
+//! future waited to be polled and whether it was dropped before completing.
+//! `StreamExt::counted` counts the items a stream yields.
+//!
+//! ```ignore
+//! use datadog_statsd::instrument::{FutureExt, StreamExt};
+//!
+//! let user = db.find_user(id).timed(&client, "db.query", None).await;
+//! let rows = db.rows().counted(&client, "db.rows", None);
+//! ```
+use crate::client::{timer_value, Client};
+use futures::{Future, Stream};
+use pin_project_lite::pin_project;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Suffix of the timer sent with the time from creating a `Timed` future to
+/// its first poll.
+pub const FIRST_POLL_SUFFIX: &str = ".first_poll";
+
+/// Suffix of the counter incremented when a `Timed` future is dropped before
+/// it completes.
+pub const CANCELLED_SUFFIX: &str = ".cancelled";
+
+/// Instruments any `Future` with a `Client`.
+pub trait FutureExt: Future + Sized {
+    /// Time this future.
+    ///
+    /// Three metrics are sent, all with `tags`:
+    ///
+    /// * `metric`, a timer with the time from calling `timed` until the future
+    ///   completes.
+    /// * `metric.first_poll`, a timer with the time from calling `timed` until
+    ///   the future is first polled, e.g. how long a task waited to be run.
+    /// * `metric.cancelled`, a counter incremented when the future is dropped
+    ///   before it completes.
+    fn timed(self, client: &Client, metric: &str, tags: Option<&Vec<&str>>) -> Timed<Self> {
+        Timed {
+            future: self,
+            state: TimedState {
+                handle: Handle::new(client, metric, tags),
+                created: Instant::now(),
+                polled: false,
+                done: false,
+            },
+        }
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+/// Instruments any `Stream` with a `Client`.
+pub trait StreamExt: Stream + Sized {
+    /// Increment the counter `metric`, with `tags`, for every item this
+    /// stream yields.
+    fn counted(self, client: &Client, metric: &str, tags: Option<&Vec<&str>>) -> Counted<Self> {
+        Counted {
+            stream: self,
+            handle: Handle::new(client, metric, tags),
+        }
+    }
+}
+
+impl<S: Stream> StreamExt for S {}
+
+/// The client, metric name and tags an instrumented future or stream sends.
+struct Handle {
+    client: Client,
+    metric: String,
+    tags: Vec<String>,
+}
+
+impl Handle {
+    fn new(client: &Client, metric: &str, tags: Option<&Vec<&str>>) -> Self {
+        Handle {
+            client: client.clone(),
+            metric: metric.into(),
+            tags: tags.map_or(vec![], |v| v.iter().map(|t| t.to_string()).collect()),
+        }
+    }
+
+    fn tags(&self) -> Option<Vec<&str>> {
+        if self.tags.is_empty() {
+            None
+        } else {
+            Some(self.tags.iter().map(|t| t.as_str()).collect())
+        }
+    }
+
+    fn timer(&self, suffix: &str, start: Instant) {
+        let value = timer_value(start.elapsed());
+        let metric = format!("{}{}", self.metric, suffix);
+        self.client.timer(&metric, value, self.tags().as_ref());
+    }
+
+    fn incr(&self, suffix: &str) {
+        let metric = format!("{}{}", self.metric, suffix);
+        self.client.incr(&metric, self.tags().as_ref());
+    }
+}
+
+/// What a `Timed` future keeps besides the future itself. Counts the
+/// cancellation when dropped, so `Timed` needs no `Drop` impl of its own.
+struct TimedState {
+    handle: Handle,
+    created: Instant,
+    polled: bool,
+    done: bool,
+}
+
+impl Drop for TimedState {
+    fn drop(&mut self) {
+        if !self.done {
+            self.handle.incr(CANCELLED_SUFFIX);
+        }
+    }
+}
+
+pin_project! {
+    /// A future that sends how long it took. Created by `FutureExt::timed`.
+    #[must_use = "futures do nothing unless polled"]
+    pub struct Timed<F> {
+        #[pin]
+        future: F,
+        state: TimedState,
+    }
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let state = this.state;
+        if !state.polled {
+            state.polled = true;
+            state.handle.timer(FIRST_POLL_SUFFIX, state.created);
+        }
+        let output = match this.future.poll(cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => return Poll::Pending,
+        };
+        // Polling again after completion isn't allowed, but don't send twice.
+        if !state.done {
+            state.done = true;
+            state.handle.timer("", state.created);
+        }
+        Poll::Ready(output)
+    }
+}
+
+pin_project! {
+    /// A stream that counts its items. Created by `StreamExt::counted`.
+    #[must_use = "streams do nothing unless polled"]
+    pub struct Counted<S> {
+        #[pin]
+        stream: S,
+        handle: Handle,
+    }
+}
+
+impl<S: Stream> Stream for Counted<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.project();
+        let item = this.stream.poll_next(cx);
+        if let Poll::Ready(Some(_)) = item {
+            this.handle.incr("");
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::prefixed_client;
+    use futures::executor::block_on;
+    use futures::FutureExt as _;
+
+    #[test]
+    fn test_timed_future_sends_first_poll_and_total() {
+        let client = prefixed_client();
+
+        let output = block_on(async { 7 }.timed(&client, "query", Some(&vec!["db:users"])));
+        assert_eq!(output, 7);
+
+        assert_eq!(
+            client.datagrams_without_timings(),
+            vec![
+                "myapp.query.first_poll:_|ms|#db:users",
+                "myapp.query:_|ms|#db:users",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timed_future_counts_cancellations() {
+        let client = prefixed_client();
+
+        // Polled once, then dropped.
+        let polled = futures::future::pending::<()>().timed(&client, "query", None);
+        assert_eq!(polled.now_or_never(), None);
+        // Never polled.
+        drop(async {}.timed(&client, "query", None));
+
+        assert_eq!(
+            client.datagrams_without_timings(),
+            vec![
+                "myapp.query.first_poll:_|ms",
+                "myapp.query.cancelled:1|c",
+                "myapp.query.cancelled:1|c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counted_stream_counts_items() {
+        use futures::StreamExt as _;
+
+        let client = prefixed_client();
+
+        let stream = futures::stream::iter(vec![1, 2, 3]).counted(&client, "items", None);
+        assert_eq!(stream.size_hint(), (3, Some(3)));
+        let items: Vec<i32> = block_on(stream.collect());
+        assert_eq!(items, vec![1, 2, 3]);
+
+        assert_eq!(
+            client.datagrams(),
+            vec!["myapp.items:1|c", "myapp.items:1|c", "myapp.items:1|c"]
+        );
+    }
+}
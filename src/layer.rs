@@ -0,0 +1,349 @@
+//! A `tracing` layer that sends span durations and error events through a
+//! `Client`.
+//!
+//! Enabled with the `tracing` feature. When a span closes, the time since it
+//! was created is sent as a timer (or distribution) tagged with the span's
+//! name and any allowed span fields. Error-level events can also be sent as
+//! Datadog events.
+//!
+//! ```ignore
+//! use datadog_statsd::layer::Layer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let layer = Layer::new(client)
+//!     .allowed_fields(vec!["route", "method"])
+//!     .error_events(true);
+//! tracing_subscriber::registry().with(layer).init();
+//! ```
+use crate::client::{sanitize_tag, timer_value, AlertType, Client, Event};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Default name of the span duration metric.
+pub const DEFAULT_SPAN_METRIC: &str = "span.duration";
+
+/// Sends span durations, and optionally error events, with a `Client`.
+#[derive(Clone)]
+pub struct Layer {
+    client: Client,
+    metric: String,
+    distribution: bool,
+    allowed_fields: HashSet<String>,
+    error_events: bool,
+}
+
+impl Layer {
+    pub fn new(client: Client) -> Self {
+        Layer {
+            client,
+            metric: DEFAULT_SPAN_METRIC.into(),
+            distribution: false,
+            allowed_fields: HashSet::new(),
+            error_events: false,
+        }
+    }
+
+    /// The metric span durations are sent as. Defaults to `span.duration`.
+    pub fn metric(mut self, metric: &str) -> Self {
+        self.metric = metric.into();
+        self
+    }
+
+    /// Send span durations as distributions rather than timers.
+    pub fn distribution(mut self, distribution: bool) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Span fields whose values are sent as `field:value` tags. No fields
+    /// are sent by default, to keep the number of tag values bounded.
+    pub fn allowed_fields(mut self, allowed_fields: Vec<&str>) -> Self {
+        self.allowed_fields = allowed_fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    /// Send error-level events as Datadog events with `AlertType::Error`.
+    pub fn error_events(mut self, error_events: bool) -> Self {
+        self.error_events = error_events;
+        self
+    }
+}
+
+/// Replace the character that would end an event's title or text. Newlines
+/// are escaped by `Event`.
+fn sanitize_event(value: &str) -> String {
+    value.replace('|', "_")
+}
+
+/// What's kept in a span's extensions until it closes.
+struct SpanTiming {
+    start: Instant,
+    name: &'static str,
+    // The latest value of each allowed field, in the order first recorded.
+    fields: Vec<(&'static str, String)>,
+}
+
+impl SpanTiming {
+    fn tags(&self) -> Vec<String> {
+        let mut tags = vec![format!("span:{}", sanitize_tag(self.name))];
+        tags.extend(
+            self.fields
+                .iter()
+                .map(|(name, value)| format!("{}:{}", name, value)),
+        );
+        tags
+    }
+}
+
+/// Collects allowed fields to send as tags.
+struct TagVisitor<'a> {
+    allowed_fields: &'a HashSet<String>,
+    fields: &'a mut Vec<(&'static str, String)>,
+}
+
+impl<'a> TagVisitor<'a> {
+    fn record(&mut self, field: &Field, value: String) {
+        if !self.allowed_fields.contains(field.name()) {
+            return;
+        }
+        let value = sanitize_tag(&value);
+        match self
+            .fields
+            .iter_mut()
+            .find(|(name, _)| *name == field.name())
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.fields.push((field.name(), value)),
+        }
+    }
+}
+
+impl<'a> Visit for TagVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+/// Collects an event's message and other fields.
+#[derive(Default)]
+struct EventVisitor {
+    message: Option<String>,
+    fields: Vec<String>,
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(sanitize_event(value));
+        } else {
+            self.fields
+                .push(format!("{}={}", field.name(), sanitize_event(value)));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = sanitize_event(&format!("{:?}", value));
+        if field.name() == "message" {
+            self.message = Some(value);
+        } else {
+            self.fields.push(format!("{}={}", field.name(), value));
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut fields = vec![];
+        attrs.record(&mut TagVisitor {
+            allowed_fields: &self.allowed_fields,
+            fields: &mut fields,
+        });
+        span.extensions_mut().insert(SpanTiming {
+            start: Instant::now(),
+            name: attrs.metadata().name(),
+            fields,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if self.allowed_fields.is_empty() {
+            return;
+        }
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            values.record(&mut TagVisitor {
+                allowed_fields: &self.allowed_fields,
+                fields: &mut timing.fields,
+            });
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        if !self.error_events || *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        let title = visitor
+            .message
+            .unwrap_or_else(|| sanitize_event(event.metadata().name()));
+        let text = if visitor.fields.is_empty() {
+            event.metadata().target().to_string()
+        } else {
+            visitor.fields.join("\n")
+        };
+        // Tag the event like the span it happened in.
+        let span_tags = ctx.event_span(event).and_then(|span| {
+            span.extensions()
+                .get::<SpanTiming>()
+                .map(|timing| timing.tags())
+        });
+        let tags: Option<Vec<&str>> = span_tags
+            .as_ref()
+            .map(|tags| tags.iter().map(|t| t.as_str()).collect());
+        self.client.send_event(
+            &Event::new(&title, &text).alert_type(AlertType::Error),
+            tags.as_ref(),
+        );
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let timing = match span.extensions_mut().remove::<SpanTiming>() {
+            Some(timing) => timing,
+            None => return,
+        };
+        let used = timing.start.elapsed();
+        let tags = timing.tags();
+        let tags: Vec<&str> = tags.iter().map(|t| t.as_str()).collect();
+        if self.distribution {
+            let value = self.client.duration_value(used);
+            self.client.distribution(&self.metric, value, Some(&tags));
+        } else {
+            self.client
+                .timer(&self.metric, timer_value(used), Some(&tags));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{prefixed_client, CapturingClient};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn make_layer(client: &CapturingClient) -> Layer {
+        Layer::new(client.client().clone())
+    }
+
+    #[test]
+    fn test_sends_span_durations_with_allowed_fields() {
+        let client = prefixed_client();
+        let layer = make_layer(&client).allowed_fields(vec!["route", "status"]);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "request",
+                route = "home",
+                user = 42,
+                status = tracing::field::Empty
+            );
+            span.record("status", 200);
+            let _enter = span.enter();
+        });
+
+        let datagrams = client.datagrams_without_timings();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(
+            datagrams[0],
+            "myapp.span.duration:_|ms|#span:request,route:home,status:200"
+        );
+    }
+
+    #[test]
+    fn test_rerecorded_fields_replace_their_tag() {
+        let client = prefixed_client();
+        let layer = make_layer(&client).allowed_fields(vec!["status", "path"]);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", status = 200, path = "/a,b|c\nd");
+            span.record("status", 500);
+            let _enter = span.enter();
+        });
+
+        let datagrams = client.datagrams_without_timings();
+        assert_eq!(
+            datagrams[0],
+            "myapp.span.duration:_|ms|#span:request,status:500,path:/a_b_c_d"
+        );
+    }
+
+    #[test]
+    fn test_sends_span_durations_as_distributions() {
+        let client = prefixed_client();
+        let layer = make_layer(&client).metric("db.query").distribution(true);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("query").in_scope(|| {});
+        });
+
+        let datagrams = client.datagrams_without_timings();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0], "myapp.db.query:_|d|#span:query");
+    }
+
+    #[test]
+    fn test_sends_error_events() {
+        let client = prefixed_client();
+        let layer = make_layer(&client)
+            .allowed_fields(vec!["route"])
+            .error_events(true);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("not sent");
+            tracing::info_span!("request", route = "home").in_scope(|| {
+                tracing::error!(code = 500, "request failed");
+                tracing::error!(reason = "a|b", "request|failed");
+            });
+        });
+
+        let datagrams = client.datagrams_without_timings();
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(
+            datagrams[0],
+            "_e{14,8}:request failed|code=500|t:error|#span:request,route:home"
+        );
+        assert_eq!(
+            datagrams[1],
+            "_e{14,10}:request_failed|reason=a_b|t:error|#span:request,route:home"
+        );
+    }
+}
@@ -7,5 +7,28 @@
 //! will be received by the server, and there is (by design) no indication of
 //! this.
 //!
+pub mod aggregator;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub mod buffer;
 pub mod client;
-pub use client::{Client, ClientConfig, StatsdError};
+pub mod instrument;
+#[cfg(feature = "tracing")]
+pub mod layer;
+pub mod origin;
+pub mod packet;
+#[cfg(feature = "metrics")]
+pub mod recorder;
+pub mod telemetry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timer;
+pub mod transport;
+pub use client::{
+    Client, ClientConfig, DurationUnit, ErrorHandler, OutcomeTags, StatsdAddr, StatsdError,
+    ToStatsdAddr,
+};
+pub use transport::Transport;
+
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncClient;
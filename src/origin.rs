@@ -0,0 +1,172 @@
+//! Container ID detection for origin detection.
+//!
+//! When UDS credentials are unavailable, the agent relies on the `|c:<id>`
+//! field of each datagram to tell which container a metric came from. The ID
+//! is read from `/proc/self/cgroup` (cgroup v1, and v2 hosts without a private
+//! cgroup namespace) or, failing that, from `/proc/self/mountinfo`.
+use std::fs;
+
+const CGROUP_PATH: &str = "/proc/self/cgroup";
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// Prefixes container runtimes put in front of the ID in systemd cgroup paths.
+const CGROUP_ID_PREFIXES: [&str; 4] = ["docker-", "cri-containerd-", "crio-", "libpod-"];
+
+/// Files every container runtime mounts from the container's own directory.
+const MOUNTINFO_MARKERS: [&str; 3] = ["hostname", "hosts", "resolv.conf"];
+
+/// Read the current container's ID, if running in one.
+pub fn detect_container_id() -> Option<String> {
+    fs::read_to_string(CGROUP_PATH)
+        .ok()
+        .and_then(|cgroup| parse_cgroup(&cgroup))
+        .or_else(|| {
+            fs::read_to_string(MOUNTINFO_PATH)
+                .ok()
+                .and_then(|mountinfo| parse_mountinfo(&mountinfo))
+        })
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Docker and containerd IDs are 64 hex characters, ECS task containers are
+/// `<32 hex>-<digits>` and some runtimes use UUIDs.
+fn is_container_id(s: &str) -> bool {
+    if is_hex(s, 64) {
+        return true;
+    }
+    if let Some((task, n)) = s.split_once('-') {
+        if is_hex(task, 32) && !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) {
+            return true;
+        }
+    }
+    let parts: Vec<&str> = s.split('-').collect();
+    parts.len() == 5
+        && parts
+            .iter()
+            .zip(&[8, 4, 4, 4, 12])
+            .all(|(part, len)| is_hex(part, *len))
+}
+
+/// Find a container ID in the last segment of a cgroup path, e.g.
+/// `/docker/<id>` or `/system.slice/docker-<id>.scope`.
+fn container_id_from_cgroup_path(path: &str) -> Option<String> {
+    let segment = path.rsplit('/').next()?;
+    let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+    let segment = CGROUP_ID_PREFIXES
+        .iter()
+        .find_map(|p| segment.strip_prefix(p))
+        .unwrap_or(segment);
+    if is_container_id(segment) {
+        Some(segment.to_string())
+    } else {
+        None
+    }
+}
+
+/// Parse the contents of `/proc/self/cgroup`, whose lines look like
+/// `<hierarchy>:<controllers>:<path>`.
+pub(crate) fn parse_cgroup(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .find_map(container_id_from_cgroup_path)
+}
+
+/// Parse the contents of `/proc/self/mountinfo`, looking for files mounted
+/// from a container's directory, e.g. `/var/lib/docker/containers/<id>/hostname`.
+pub(crate) fn parse_mountinfo(mountinfo: &str) -> Option<String> {
+    mountinfo
+        .lines()
+        .flat_map(|line| line.split_whitespace())
+        .filter(|field| !field.contains("/sandboxes/"))
+        .find_map(|field| {
+            let segments: Vec<&str> = field.split('/').collect();
+            segments.windows(2).find_map(|w| {
+                if MOUNTINFO_MARKERS.contains(&w[1]) && is_hex(w[0], 64) {
+                    Some(w[0].to_string())
+                } else {
+                    None
+                }
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ID: &str = "3726184226f5d3147c25fdeab5b60097e378e8a720503a5e19ecfdf29f869860";
+
+    #[test]
+    fn test_parse_cgroup_v1() {
+        let cgroup = format!(
+            "12:pids:/docker/{id}\n11:hugetlb:/docker/{id}\n1:name=systemd:/docker/{id}\n",
+            id = ID
+        );
+        assert_eq!(parse_cgroup(&cgroup), Some(ID.to_string()));
+    }
+
+    #[test]
+    fn test_parse_cgroup_systemd_scope() {
+        let cgroup = format!("0::/system.slice/docker-{}.scope\n", ID);
+        assert_eq!(parse_cgroup(&cgroup), Some(ID.to_string()));
+
+        let cgroup = format!(
+            "1:name=systemd:/kubepods.slice/kubepods-pod1.slice/cri-containerd-{}.scope\n",
+            ID
+        );
+        assert_eq!(parse_cgroup(&cgroup), Some(ID.to_string()));
+    }
+
+    #[test]
+    fn test_parse_cgroup_ecs_and_uuid() {
+        let cgroup = "9:perf_event:/ecs/task/34dc0b5e626f2c5c4c5170e34b10e765-1234567890\n";
+        assert_eq!(
+            parse_cgroup(cgroup),
+            Some("34dc0b5e626f2c5c4c5170e34b10e765-1234567890".to_string())
+        );
+
+        let cgroup = "1:name=systemd:/uuid/34dc0b5e-626f-2c5c-4c51-70e34b10e765\n";
+        assert_eq!(
+            parse_cgroup(cgroup),
+            Some("34dc0b5e-626f-2c5c-4c51-70e34b10e765".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_cgroup_without_container() {
+        assert_eq!(parse_cgroup("0::/\n"), None);
+        assert_eq!(
+            parse_cgroup("0::/user.slice/user-1000.slice/session-1.scope\n"),
+            None
+        );
+        assert_eq!(parse_cgroup(""), None);
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = format!(
+            "608 589 0:53 / / rw,relatime master:295 - overlay overlay rw\n\
+             625 608 259:1 /var/lib/docker/containers/{id}/resolv.conf /etc/resolv.conf rw,relatime - ext4 /dev/nvme0n1p1 rw\n\
+             626 608 259:1 /var/lib/docker/containers/{id}/hostname /etc/hostname rw,relatime - ext4 /dev/nvme0n1p1 rw\n",
+            id = ID
+        );
+        assert_eq!(parse_mountinfo(&mountinfo), Some(ID.to_string()));
+    }
+
+    #[test]
+    fn test_parse_mountinfo_skips_sandboxes() {
+        let mountinfo = format!(
+            "1 2 0:1 /var/lib/containerd/io.containerd.grpc.v1.cri/sandboxes/{}/hostname /etc/hostname rw - ext4 /dev/sda rw\n",
+            ID
+        );
+        assert_eq!(parse_mountinfo(&mountinfo), None);
+        assert_eq!(
+            parse_mountinfo("608 589 0:53 / / rw - overlay overlay rw\n"),
+            None
+        );
+    }
+}
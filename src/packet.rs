@@ -0,0 +1,153 @@
+//! Packing of newline separated lines into size-bounded datagrams.
+//!
+//! `PacketBuilder` is shared by `Pipeline::send`, the aggregation and
+//! telemetry flushes and `BufferedTransport`, so every path that batches
+//! lines gives the same guarantee: no packet it returns is larger than its
+//! max size, and a line that could never fit is dropped and reported rather
+//! than sent.
+use crate::client::StatsdError;
+
+/// Packs newline separated lines into packets of at most `max_size` bytes.
+pub(crate) struct PacketBuilder {
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+impl PacketBuilder {
+    pub(crate) fn new(max_size: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(max_size),
+            max_size,
+        }
+    }
+
+    /// Add a line, returning the previous packet if the line didn't fit in it.
+    ///
+    /// A line longer than `max_size` is dropped, leaving the current packet
+    /// as it was, and `PacketTooLarge` is returned. Empty lines are ignored.
+    pub(crate) fn push(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, StatsdError> {
+        if data.len() > self.max_size {
+            return Err(StatsdError::PacketTooLarge {
+                size: data.len(),
+                max: self.max_size,
+            });
+        }
+        if data.is_empty() {
+            Ok(None)
+        } else if self.buf.is_empty() {
+            self.buf.extend_from_slice(data);
+            Ok(None)
+        } else if self.buf.len() + data.len() + 1 > self.max_size {
+            let packet = std::mem::replace(&mut self.buf, Vec::with_capacity(self.max_size));
+            self.buf.extend_from_slice(data);
+            Ok(Some(packet))
+        } else {
+            self.buf.push(b'\n');
+            self.buf.extend_from_slice(data);
+            Ok(None)
+        }
+    }
+
+    /// Take the partial packet, if any.
+    pub(crate) fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(std::mem::replace(
+                &mut self.buf,
+                Vec::with_capacity(self.max_size),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_packs_up_to_max_size() {
+        let mut builder = PacketBuilder::new(21);
+        assert_eq!(builder.push(b"metric:1|c").unwrap(), None);
+        assert_eq!(builder.push(b"metric:2|c").unwrap(), None);
+        assert_eq!(
+            builder.push(b"metric:3|c").unwrap(),
+            Some(b"metric:1|c\nmetric:2|c".to_vec())
+        );
+        assert_eq!(builder.finish(), Some(b"metric:3|c".to_vec()));
+        assert_eq!(builder.finish(), None);
+    }
+
+    #[test]
+    fn test_drops_lines_that_cannot_fit() {
+        let mut builder = PacketBuilder::new(10);
+        assert_eq!(builder.push(b"metric:1|c").unwrap(), None);
+        match builder.push(b"metric:100|c") {
+            Err(StatsdError::PacketTooLarge { size: 12, max: 10 }) => {}
+            other => panic!("expected PacketTooLarge, got {:?}", other),
+        }
+        assert_eq!(builder.finish(), Some(b"metric:1|c".to_vec()));
+    }
+
+    /// Push every line, returning the packets built and the lines dropped.
+    fn pack(lines: &[Vec<u8>], max_size: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut builder = PacketBuilder::new(max_size);
+        let mut packets = vec![];
+        let mut dropped = vec![];
+        for line in lines {
+            match builder.push(line) {
+                Ok(packet) => packets.extend(packet),
+                Err(_) => dropped.push(line.clone()),
+            }
+        }
+        packets.extend(builder.finish());
+        (packets, dropped)
+    }
+
+    fn lines() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        let line = proptest::collection::vec(
+            any::<u8>().prop_filter("no newline", |b| *b != b'\n'),
+            0..80,
+        );
+        proptest::collection::vec(line, 0..50)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_packets_fit_in_max_size(lines in lines(), max_size in 0usize..200) {
+            let (packets, _) = pack(&lines, max_size);
+            for packet in packets {
+                prop_assert!(!packet.is_empty());
+                prop_assert!(packet.len() <= max_size);
+            }
+        }
+
+        #[test]
+        fn prop_every_line_that_fits_is_sent_in_order(lines in lines(), max_size in 0usize..200) {
+            let (packets, dropped) = pack(&lines, max_size);
+            let sent: Vec<Vec<u8>> = packets
+                .iter()
+                .flat_map(|p| p.split(|b| *b == b'\n'))
+                .map(|l| l.to_vec())
+                .collect();
+            let expected: Vec<Vec<u8>> = lines
+                .iter()
+                .filter(|l| !l.is_empty() && l.len() <= max_size)
+                .cloned()
+                .collect();
+            prop_assert_eq!(sent, expected);
+            prop_assert!(dropped.iter().all(|l| l.len() > max_size));
+        }
+
+        #[test]
+        fn prop_packets_are_only_split_when_full(lines in lines(), max_size in 1usize..200) {
+            let (packets, _) = pack(&lines, max_size);
+            // Each packet was closed because the next line didn't fit in it.
+            for pair in packets.windows(2) {
+                let next_line = pair[1].split(|b| *b == b'\n').next().unwrap();
+                prop_assert!(pair[0].len() + 1 + next_line.len() > max_size);
+            }
+        }
+    }
+}
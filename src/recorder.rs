@@ -0,0 +1,293 @@
+//! A `metrics` crate recorder that sends through a `Client`.
+//!
+//! Enabled with the `metrics` feature. Counters, gauges and histograms
+//! recorded through the `metrics` facade are sent with `Client::count`,
+//! `Client::gauge` and `Client::histogram` (or `Client::distribution`), and
+//! their labels become `key:value` tags.
+//!
+//! ```ignore
+//! use datadog_statsd::recorder::Recorder;
+//! use datadog_statsd::{Client, ClientConfig};
+//!
+//! let config = ClientConfig::builder(("127.0.0.1", 8125)).prefix("myapp").build();
+//! Recorder::new(Client::new(&config)?).install()?;
+//!
+//! metrics::counter!("requests", "route" => "home").increment(1);
+//! ```
+use crate::client::{sanitize_tag, Client};
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata,
+    SetRecorderError, SharedString, Unit,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Which DogStatsD type `metrics` histograms are sent as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramType {
+    /// Aggregated by the agent, as `Client::histogram`.
+    Histogram,
+    /// Aggregated globally by Datadog, as `Client::distribution`.
+    Distribution,
+}
+
+/// Records `metrics` counters, gauges and histograms with a `Client`.
+///
+/// Clones share their registered counters and gauges.
+#[derive(Clone)]
+pub struct Recorder {
+    client: Client,
+    histogram_type: HistogramType,
+    // Counters and gauges keep state, so each key is registered once.
+    counters: Arc<Mutex<HashMap<Key, Arc<CounterHandle>>>>,
+    gauges: Arc<Mutex<HashMap<Key, Arc<GaugeHandle>>>>,
+}
+
+impl Recorder {
+    pub fn new(client: Client) -> Self {
+        Recorder {
+            client,
+            histogram_type: HistogramType::Histogram,
+            counters: Default::default(),
+            gauges: Default::default(),
+        }
+    }
+
+    /// Send histograms as this type. Defaults to `HistogramType::Histogram`.
+    pub fn histogram_type(mut self, histogram_type: HistogramType) -> Self {
+        self.histogram_type = histogram_type;
+        self
+    }
+
+    /// Install this recorder as the global `metrics` recorder.
+    pub fn install(self) -> Result<(), SetRecorderError<Self>> {
+        metrics::set_global_recorder(self)
+    }
+
+    fn handle(&self, key: &Key) -> Handle {
+        Handle {
+            client: self.client.clone(),
+            metric: key.name().to_string(),
+            tags: key
+                .labels()
+                .map(|l| format!("{}:{}", sanitize_tag(l.key()), sanitize_tag(l.value())))
+                .collect(),
+        }
+    }
+}
+
+impl metrics::Recorder for Recorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        let counter = counters.entry(key.clone()).or_insert_with(|| {
+            Arc::new(CounterHandle {
+                handle: self.handle(key),
+                absolute: AtomicU64::new(0),
+            })
+        });
+        Counter::from_arc(Arc::clone(counter))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.gauges.lock().unwrap_or_else(|e| e.into_inner());
+        let gauge = gauges.entry(key.clone()).or_insert_with(|| {
+            Arc::new(GaugeHandle {
+                handle: self.handle(key),
+                value: AtomicU64::new(0f64.to_bits()),
+            })
+        });
+        Gauge::from_arc(Arc::clone(gauge))
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(Arc::new(HistogramHandle {
+            handle: self.handle(key),
+            histogram_type: self.histogram_type,
+        }))
+    }
+}
+
+/// The client, metric name and tags a registered metric is sent with.
+struct Handle {
+    client: Client,
+    metric: String,
+    tags: Vec<String>,
+}
+
+impl Handle {
+    fn tags(&self) -> Option<Vec<&str>> {
+        if self.tags.is_empty() {
+            None
+        } else {
+            Some(self.tags.iter().map(|t| t.as_str()).collect())
+        }
+    }
+}
+
+struct CounterHandle {
+    handle: Handle,
+    // The highest value passed to `absolute`, so only increases are counted.
+    absolute: AtomicU64,
+}
+
+impl CounterFn for CounterHandle {
+    fn increment(&self, value: u64) {
+        let h = &self.handle;
+        h.client.count(&h.metric, value as f64, h.tags().as_ref());
+    }
+
+    fn absolute(&self, value: u64) {
+        let previous = self.absolute.fetch_max(value, Ordering::AcqRel);
+        if value > previous {
+            self.increment(value - previous);
+        }
+    }
+}
+
+struct GaugeHandle {
+    handle: Handle,
+    // DogStatsD has no relative gauges, so the value is tracked here.
+    value: AtomicU64,
+}
+
+impl GaugeHandle {
+    fn update<F: Fn(f64) -> f64>(&self, f: F) {
+        let mut current = self.value.load(Ordering::Acquire);
+        let value = loop {
+            let value = f(f64::from_bits(current));
+            match self.value.compare_exchange_weak(
+                current,
+                value.to_bits(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break value,
+                Err(actual) => current = actual,
+            }
+        };
+        let h = &self.handle;
+        h.client.gauge(&h.metric, value, h.tags().as_ref());
+    }
+}
+
+impl GaugeFn for GaugeHandle {
+    fn increment(&self, value: f64) {
+        self.update(|v| v + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|v| v - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+struct HistogramHandle {
+    handle: Handle,
+    histogram_type: HistogramType,
+}
+
+impl HistogramFn for HistogramHandle {
+    fn record(&self, value: f64) {
+        let h = &self.handle;
+        match self.histogram_type {
+            HistogramType::Histogram => h.client.histogram(&h.metric, value, h.tags().as_ref()),
+            HistogramType::Distribution => {
+                h.client.distribution(&h.metric, value, h.tags().as_ref())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::prefixed_client;
+
+    #[test]
+    fn test_records_counters_with_labels() {
+        let client = prefixed_client();
+        let recorder = Recorder::new(client.client().clone());
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("requests", "route" => "home").increment(2);
+            let counter = metrics::counter!("jobs");
+            counter.absolute(5);
+            counter.absolute(3);
+            counter.absolute(7);
+        });
+
+        assert_eq!(
+            client.datagrams(),
+            vec![
+                "myapp.requests:2|c|#route:home",
+                "myapp.jobs:5|c",
+                "myapp.jobs:2|c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_records_gauges() {
+        let client = prefixed_client();
+        let recorder = Recorder::new(client.client().clone());
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::gauge!("load", "host" => "web-1").set(2.5);
+            metrics::gauge!("load", "host" => "web-1").increment(1.0);
+            metrics::gauge!("load", "host" => "web-1").decrement(0.5);
+        });
+
+        assert_eq!(
+            client.datagrams(),
+            vec![
+                "myapp.load:2.5|g|#host:web-1",
+                "myapp.load:3.5|g|#host:web-1",
+                "myapp.load:3|g|#host:web-1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_records_histograms_as_configured_type() {
+        let client = prefixed_client();
+        let recorder = Recorder::new(client.client().clone());
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!("latency").record(12.5);
+        });
+
+        let recorder = recorder.histogram_type(HistogramType::Distribution);
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!("latency").record(12.5);
+        });
+
+        assert_eq!(
+            client.datagrams(),
+            vec!["myapp.latency:12.5|h", "myapp.latency:12.5|d"]
+        );
+    }
+
+    #[test]
+    fn test_sanitizes_labels() {
+        let client = prefixed_client();
+        let recorder = Recorder::new(client.client().clone());
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("requests", "path" => "/a,b|c\nd").increment(1);
+        });
+
+        assert_eq!(
+            client.datagrams(),
+            vec!["myapp.requests:1|c|#path:/a_b_c_d"]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Client self-telemetry.
+//!
+//! Like the official Datadog clients, a `Client` counts what it sends and
+//! drops, and periodically reports those counts as `datadog.dogstatsd.client.*`
+//! metrics tagged with `client:rust`.
+use crate::client::ClientConfig;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default interval at which a client reports its telemetry.
+pub const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The kinds of metric counted by `datadog.dogstatsd.client.metrics_by_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MetricType {
+    Count,
+    Gauge,
+    Set,
+    Histogram,
+    Distribution,
+    Timing,
+}
+
+impl MetricType {
+    const ALL: [MetricType; 6] = [
+        MetricType::Count,
+        MetricType::Gauge,
+        MetricType::Set,
+        MetricType::Histogram,
+        MetricType::Distribution,
+        MetricType::Timing,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            MetricType::Count => "count",
+            MetricType::Gauge => "gauge",
+            MetricType::Set => "set",
+            MetricType::Histogram => "histogram",
+            MetricType::Distribution => "distribution",
+            MetricType::Timing => "timing",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Telemetry {
+    metrics: [AtomicU64; 6],
+    events: AtomicU64,
+    service_checks: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_dropped: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_dropped: AtomicU64,
+}
+
+impl Telemetry {
+    /// Counters for a client, if its config enables telemetry. They're shared
+    /// with any worker that writes the client's packets.
+    pub(crate) fn from_config<A>(client_config: &ClientConfig<A>) -> Option<Arc<Telemetry>> {
+        if client_config.telemetry {
+            Some(Arc::new(Telemetry::default()))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn metric(&self, metric_type: MetricType) {
+        self.metrics[metric_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn event(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn service_check(&self) {
+        self.service_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn packet_sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn packet_dropped(&self, bytes: usize) {
+        self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        self.bytes_dropped
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Take the counts since the last flush as `datadog.dogstatsd.client.*`
+    /// metric lines, each paired with its extra tag, if any.
+    pub(crate) fn flush(&self) -> Vec<(String, Option<String>)> {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        let line =
+            |name: &str, value: u64| format!("datadog.dogstatsd.client.{}:{}|c", name, value);
+
+        let by_type: Vec<(MetricType, u64)> = MetricType::ALL
+            .iter()
+            .map(|t| (*t, take(&self.metrics[*t as usize])))
+            .collect();
+        let mut lines = vec![(line("metrics", by_type.iter().map(|(_, n)| n).sum()), None)];
+        for (metric_type, n) in by_type {
+            lines.push((
+                line("metrics_by_type", n),
+                Some(format!("metrics_type:{}", metric_type.name())),
+            ));
+        }
+        for (name, counter) in &[
+            ("events", &self.events),
+            ("service_checks", &self.service_checks),
+            ("bytes_sent", &self.bytes_sent),
+            ("bytes_dropped", &self.bytes_dropped),
+            ("packets_sent", &self.packets_sent),
+            ("packets_dropped", &self.packets_dropped),
+        ] {
+            lines.push((line(name, take(counter)), None));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flush_reports_and_resets_counts() {
+        let telemetry = Telemetry::default();
+        telemetry.metric(MetricType::Count);
+        telemetry.metric(MetricType::Count);
+        telemetry.metric(MetricType::Timing);
+        telemetry.event();
+        telemetry.packet_sent(10);
+        telemetry.packet_sent(5);
+        telemetry.packet_dropped(7);
+
+        let lines = telemetry.flush();
+        let find = |name: &str, tag: Option<&str>| {
+            lines
+                .iter()
+                .find(|(l, t)| l.starts_with(name) && t.as_deref() == tag)
+                .map(|(l, _)| l.clone())
+                .unwrap()
+        };
+        assert_eq!(
+            find("datadog.dogstatsd.client.metrics:", None),
+            "datadog.dogstatsd.client.metrics:3|c"
+        );
+        assert_eq!(
+            find(
+                "datadog.dogstatsd.client.metrics_by_type:",
+                Some("metrics_type:count")
+            ),
+            "datadog.dogstatsd.client.metrics_by_type:2|c"
+        );
+        assert_eq!(
+            find("datadog.dogstatsd.client.events:", None),
+            "datadog.dogstatsd.client.events:1|c"
+        );
+        assert_eq!(
+            find("datadog.dogstatsd.client.bytes_sent:", None),
+            "datadog.dogstatsd.client.bytes_sent:15|c"
+        );
+        assert_eq!(
+            find("datadog.dogstatsd.client.packets_dropped:", None),
+            "datadog.dogstatsd.client.packets_dropped:1|c"
+        );
+
+        let lines = telemetry.flush();
+        assert!(lines.iter().all(|(l, _)| l.ends_with(":0|c")));
+    }
+}
@@ -0,0 +1,359 @@
+//! Helpers for unit testing code that emits metrics.
+//!
+//! Enabled with the `testing` feature. A `CapturingClient` is a real `Client`
+//! whose transport records every datagram in memory instead of sending it, so
+//! tests don't need to bind UDP ports or race on receives. Turn telemetry
+//! off so the client's own `datadog.dogstatsd.client.*` metrics aren't
+//! captured too.
+//!
+//! ```ignore
+//! use datadog_statsd::testing::CapturingClient;
+//! use datadog_statsd::ClientConfig;
+//!
+//! let config = ClientConfig::builder(())
+//!     .prefix("myapp")
+//!     .telemetry(false)
+//!     .build();
+//! let client = CapturingClient::new(&config);
+//!
+//! client.incr("requests", Some(&vec!["route:home"]));
+//!
+//! client.expect_incr("requests", Some(&vec!["route:home"]));
+//! ```
+use crate::client::{Client, ClientConfig, DEFAULT_UDP_PACKET_SIZE};
+use crate::transport::Transport;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// A transport that records every datagram it is given.
+///
+/// Clones share the same buffer, so one clone can be handed to a `Client`
+/// while another is kept around to inspect what was sent.
+#[derive(Clone, Default)]
+pub struct CapturingTransport {
+    datagrams: Arc<Mutex<Vec<String>>>,
+    max_packet_size: Option<usize>,
+}
+
+impl CapturingTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report a custom max packet size to the client.
+    pub fn with_max_packet_size(max_packet_size: usize) -> Self {
+        Self {
+            datagrams: Default::default(),
+            max_packet_size: Some(max_packet_size),
+        }
+    }
+
+    /// Every datagram sent so far, in order.
+    pub fn datagrams(&self) -> Vec<String> {
+        self.lock().clone()
+    }
+
+    /// Every metric line sent so far, with datagrams split on newlines.
+    pub fn lines(&self) -> Vec<String> {
+        self.lock()
+            .iter()
+            .flat_map(|d| d.split('\n'))
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    /// Every datagram sent so far, with the value of each timer and
+    /// distribution replaced by `_`, since measured durations vary between
+    /// runs.
+    pub fn datagrams_without_timings(&self) -> Vec<String> {
+        self.lock()
+            .iter()
+            .map(|d| {
+                d.split('\n')
+                    .map(without_timing)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .collect()
+    }
+
+    /// Remove and return every datagram sent so far.
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// Forget every datagram sent so far.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<String>> {
+        self.datagrams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Replace the value of a timer or distribution line with `_`.
+fn without_timing(line: &str) -> String {
+    let parts = line
+        .split_once(':')
+        .and_then(|(name, rest)| Some((name, rest.split_once('|')?.1)));
+    match parts {
+        Some((name, rest)) if matches!(rest.split('|').next(), Some("ms") | Some("d")) => {
+            format!("{}:_|{}", name, rest)
+        }
+        _ => line.to_string(),
+    }
+}
+
+impl Transport for CapturingTransport {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.lock().push(String::from_utf8_lossy(data).into_owned());
+        Ok(data.len())
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size.unwrap_or(DEFAULT_UDP_PACKET_SIZE)
+    }
+}
+
+/// A `Client` that captures everything it sends.
+///
+/// Derefs to `Client`, so it can be used anywhere a `&Client` is expected.
+/// The `expect_*` helpers format the expected line exactly as the client
+/// would, including the prefix and constant tags, and panic with the
+/// captured lines if it was never sent.
+pub struct CapturingClient {
+    client: Client,
+    transport: CapturingTransport,
+}
+
+impl CapturingClient {
+    /// Construct a capturing client. The config's address is ignored.
+    pub fn new<A>(client_config: &ClientConfig<A>) -> Self {
+        Self::with_transport(CapturingTransport::new(), client_config)
+    }
+
+    /// Construct a capturing client around an existing capturing transport.
+    pub fn with_transport<A>(
+        transport: CapturingTransport,
+        client_config: &ClientConfig<A>,
+    ) -> Self {
+        Self {
+            client: Client::with_transport(transport.clone(), client_config),
+            transport,
+        }
+    }
+
+    /// The underlying client.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The transport holding the captured datagrams.
+    pub fn transport(&self) -> &CapturingTransport {
+        &self.transport
+    }
+
+    /// Every datagram sent so far, in order.
+    ///
+    /// The client is flushed first, so buffered metrics are included.
+    pub fn datagrams(&self) -> Vec<String> {
+        self.client.flush();
+        self.transport.datagrams()
+    }
+
+    /// Every metric line sent so far, with datagrams split on newlines.
+    ///
+    /// The client is flushed first, so buffered metrics are included.
+    pub fn lines(&self) -> Vec<String> {
+        self.client.flush();
+        self.transport.lines()
+    }
+
+    /// Every datagram sent so far, with the value of each timer and
+    /// distribution replaced by `_`.
+    ///
+    /// The client is flushed first, so buffered metrics are included.
+    pub fn datagrams_without_timings(&self) -> Vec<String> {
+        self.client.flush();
+        self.transport.datagrams_without_timings()
+    }
+
+    /// Whether exactly this line was sent.
+    pub fn contains(&self, line: &str) -> bool {
+        self.lines().iter().any(|l| l == line)
+    }
+
+    /// Assert that exactly this line was sent.
+    pub fn expect_line(&self, line: &str) {
+        let lines = self.lines();
+        assert!(
+            lines.iter().any(|l| l == line),
+            "expected metric `{}` to be sent, got {:#?}",
+            line,
+            lines
+        );
+    }
+
+    /// Assert that a counter was modified by `value`.
+    pub fn expect_count(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.expect_metric(metric, value, "c", tags);
+    }
+
+    /// Assert that a counter was incremented by 1.
+    pub fn expect_incr(&self, metric: &str, tags: Option<&Vec<&str>>) {
+        self.expect_count(metric, 1.0, tags);
+    }
+
+    /// Assert that a counter was decremented by 1.
+    pub fn expect_decr(&self, metric: &str, tags: Option<&Vec<&str>>) {
+        self.expect_count(metric, -1.0, tags);
+    }
+
+    /// Assert that a gauge was set to `value`.
+    pub fn expect_gauge(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.expect_metric(metric, value, "g", tags);
+    }
+
+    /// Assert that a timer value was sent.
+    pub fn expect_timer(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.expect_metric(metric, value, "ms", tags);
+    }
+
+    /// Assert that a histogram value was sent.
+    pub fn expect_histogram(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.expect_metric(metric, value, "h", tags);
+    }
+
+    /// Assert that a distribution value was sent.
+    pub fn expect_distribution(&self, metric: &str, value: f64, tags: Option<&Vec<&str>>) {
+        self.expect_metric(metric, value, "d", tags);
+    }
+
+    /// Assert that a set value was sent.
+    pub fn expect_set<V: fmt::Display>(&self, metric: &str, value: V, tags: Option<&Vec<&str>>) {
+        self.expect_metric(metric, value, "s", tags);
+    }
+
+    fn expect_metric<V: fmt::Display>(
+        &self,
+        metric: &str,
+        value: V,
+        metric_type: &str,
+        tags: Option<&Vec<&str>>,
+    ) {
+        let line = self
+            .client
+            .prepare_with_tags(format!("{}:{}|{}", metric, value, metric_type), tags);
+        self.expect_line(&line);
+    }
+}
+
+impl Deref for CapturingClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+/// A capturing client with the `myapp` prefix, shared by the crate's tests.
+#[cfg(test)]
+pub(crate) fn prefixed_client() -> CapturingClient {
+    let config = ClientConfig::builder(())
+        .prefix("myapp")
+        .telemetry(false)
+        .build();
+    CapturingClient::new(&config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_client() -> CapturingClient {
+        let config = ClientConfig::builder(())
+            .prefix("myapp")
+            .constant_tags(vec!["tag1common"])
+            .telemetry(false)
+            .build();
+        CapturingClient::new(&config)
+    }
+
+    #[test]
+    fn test_capturing_client_records_datagrams() {
+        let client = make_client();
+
+        client.incr("metric", None);
+        client.gauge("metric", 9.1, Some(&vec!["tag1"]));
+
+        assert_eq!(
+            client.datagrams(),
+            vec![
+                "myapp.metric:1|c|#tag1common",
+                "myapp.metric:9.1|g|#tag1common,tag1"
+            ]
+        );
+        client.expect_incr("metric", None);
+        client.expect_gauge("metric", 9.1, Some(&vec!["tag1"]));
+        assert!(!client.contains("myapp.metric:9.1|g|#tag1common"));
+    }
+
+    #[test]
+    fn test_capturing_client_splits_pipeline_lines() {
+        let client = make_client();
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("metric", 9.1, None);
+        pipeline.count("metric", 12.2, None);
+        pipeline.send(&client);
+
+        assert_eq!(client.datagrams().len(), 1);
+        assert_eq!(
+            client.lines(),
+            vec![
+                "myapp.metric:9.1|g|#tag1common",
+                "myapp.metric:12.2|c|#tag1common"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_datagrams_without_timings() {
+        let client = make_client();
+        client.timer("query", 12.5, None);
+        let mut pipeline = client.pipeline();
+        pipeline.distribution("query", 3.0, None);
+        pipeline.gauge("load", 2.5, None);
+        pipeline.send(&client);
+
+        assert_eq!(
+            client.datagrams_without_timings(),
+            vec![
+                "myapp.query:_|ms|#tag1common",
+                "myapp.query:_|d|#tag1common\nmyapp.load:2.5|g|#tag1common"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capturing_transport_take() {
+        let transport = CapturingTransport::with_max_packet_size(64);
+        let config = ClientConfig::builder(()).telemetry(false).build();
+        let client = CapturingClient::with_transport(transport.clone(), &config);
+        assert_eq!(client.pipeline().max_udp_size(), 64);
+
+        client.set("users", "user-123", None);
+        assert_eq!(transport.take(), vec!["users:user-123|s"]);
+        assert!(client.datagrams().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "expected metric `myapp.metric:2|c|#tag1common` to be sent")]
+    fn test_expect_count_panics_when_missing() {
+        let client = make_client();
+        client.incr("metric", None);
+        client.expect_count("metric", 2.0, None);
+    }
+}
@@ -0,0 +1,130 @@
+//! A guard that times the code between its creation and its drop.
+//!
+//! Unlike `Client::time`, a `TimerGuard` doesn't need the timed code to be
+//! wrapped in a closure, so early returns and `?` are still timed.
+//!
+//! ```ignore
+//! fn handle(client: &Client) -> Result<(), Error> {
+//!     let mut timer = client.start_timer("request.duration", None);
+//!     let response = fetch()?;
+//!     timer.add_tag(&format!("status:{}", response.status));
+//!     Ok(())
+//! }
+//! ```
+use crate::client::{timer_value, Client};
+use std::time::{Duration, Instant};
+
+/// Sends the time since it was started as a timer when dropped.
+///
+/// Created by `Client::start_timer`.
+#[must_use = "the timer is sent when the guard is dropped"]
+pub struct TimerGuard {
+    client: Client,
+    metric: String,
+    tags: Vec<String>,
+    start: Instant,
+    done: bool,
+}
+
+impl TimerGuard {
+    pub(crate) fn new(client: Client, metric: &str, tags: Option<&Vec<&str>>) -> Self {
+        TimerGuard {
+            client,
+            metric: metric.into(),
+            tags: tags.map_or(vec![], |v| v.iter().map(|t| t.to_string()).collect()),
+            start: Instant::now(),
+            done: false,
+        }
+    }
+
+    /// Add a tag to send with the timer, e.g. an outcome known only at the end.
+    pub fn add_tag(&mut self, tag: &str) {
+        self.tags.push(tag.into());
+    }
+
+    /// The time since the timer was started.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Send the timer now rather than when the guard is dropped, returning
+    /// the time that was sent.
+    pub fn stop(mut self) -> Duration {
+        self.send()
+    }
+
+    /// Drop the guard without sending the timer.
+    pub fn cancel(mut self) {
+        self.done = true;
+    }
+
+    fn send(&mut self) -> Duration {
+        let used = self.start.elapsed();
+        self.done = true;
+        let tags: Vec<&str> = self.tags.iter().map(|t| t.as_str()).collect();
+        let tags = Some(&tags).filter(|t| !t.is_empty());
+        let value = timer_value(used);
+        self.client.timer(&self.metric, value, tags);
+        used
+    }
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            self.send();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::Client;
+    use crate::testing::prefixed_client;
+
+    #[test]
+    fn test_timer_guard_sends_on_drop() {
+        let client = prefixed_client();
+
+        fn work(client: &Client, fail: bool) -> Result<(), ()> {
+            let mut timer = client.start_timer("request", Some(&vec!["route:home"]));
+            if fail {
+                return Err(());
+            }
+            timer.add_tag("status:ok");
+            Ok(())
+        }
+        work(&client, true).unwrap_err();
+        work(&client, false).unwrap();
+
+        assert_eq!(
+            client.datagrams_without_timings(),
+            vec![
+                "myapp.request:_|ms|#route:home",
+                "myapp.request:_|ms|#route:home,status:ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timer_guard_stop_and_cancel() {
+        let client = prefixed_client();
+
+        let timer = client.start_timer("stopped", None);
+        let used = timer.stop();
+        client.start_timer("cancelled", None).cancel();
+
+        assert_eq!(
+            client.datagrams_without_timings(),
+            vec!["myapp.stopped:_|ms"]
+        );
+        // The value sent is the duration `stop` returned, in milliseconds.
+        let value: f64 = client.datagrams()[0]
+            .strip_prefix("myapp.stopped:")
+            .and_then(|d| d.strip_suffix("|ms"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(value, used.as_secs_f64() * 1000.0);
+    }
+}
@@ -0,0 +1,192 @@
+//! Transports carry formatted DogStatsD datagrams to the agent.
+//!
+//! A `Client` writes every packet through a `Transport`. UDP and unix domain
+//! sockets are provided here, and anything else (TCP, an in-memory buffer, a
+//! test sink) can be plugged in with `Client::with_transport`.
+use crate::client::{StatsdAddr, StatsdError};
+use std::io;
+#[cfg(unix)]
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::time;
+
+/// Default max packet size for UDP, which fits in a datagram on a network
+/// with the usual 1500 byte MTU.
+pub const DEFAULT_UDP_PACKET_SIZE: usize = 1432;
+
+/// Default max packet size for unix domain sockets, matching the agent's default buffer.
+pub const DEFAULT_UDS_PACKET_SIZE: usize = 8192;
+
+#[cfg(unix)]
+const UDS_WRITE_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+/// A sink for DogStatsD datagrams.
+///
+/// Each call to `send` carries one complete datagram, which may hold several
+/// newline separated metrics.
+pub trait Transport: Send + Sync {
+    /// Send a single datagram, returning the number of bytes written.
+    fn send(&self, data: &[u8]) -> io::Result<usize>;
+
+    /// Flush anything the transport has buffered.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The largest datagram this transport should be given.
+    fn max_packet_size(&self) -> usize {
+        DEFAULT_UDP_PACKET_SIZE
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        (**self).send(data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn max_packet_size(&self) -> usize {
+        (**self).max_packet_size()
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        (**self).send(data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn max_packet_size(&self) -> usize {
+        (**self).max_packet_size()
+    }
+}
+
+/// Build the default transport for an address.
+pub(crate) fn connect(addr: StatsdAddr) -> Result<Box<dyn Transport>, StatsdError> {
+    match addr {
+        StatsdAddr::Udp(addr) => Ok(Box::new(UdpTransport::connect(addr)?)),
+        #[cfg(unix)]
+        StatsdAddr::UnixDatagram(path) => Ok(Box::new(UnixDatagramTransport::connect(path)?)),
+        #[cfg(unix)]
+        StatsdAddr::UnixStream(path) => Ok(Box::new(UnixStreamTransport::connect(path))),
+        #[cfg(not(unix))]
+        StatsdAddr::UnixDatagram(_) | StatsdAddr::UnixStream(_) => Err(
+            StatsdError::AddrParseError("unix sockets are not supported on this platform".into()),
+        ),
+    }
+}
+
+/// Sends datagrams over UDP.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn connect(addr: SocketAddr) -> Result<Self, StatsdError> {
+        // Bind to a generic port as we'll only be writing on this
+        // socket.
+        let socket = if addr.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        Ok(Self { socket, addr })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(data, self.addr)
+    }
+}
+
+/// Sends datagrams over a unix domain socket in datagram mode.
+#[cfg(unix)]
+pub struct UnixDatagramTransport {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixDatagramTransport {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, StatsdError> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_write_timeout(Some(UDS_WRITE_TIMEOUT))?;
+        Ok(Self {
+            socket,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixDatagramTransport {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(data, &self.path)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        DEFAULT_UDS_PACKET_SIZE
+    }
+}
+
+/// Sends datagrams over a unix domain socket in stream mode.
+///
+/// The connection is (re)established lazily so that the client survives
+/// agent restarts.
+#[cfg(unix)]
+pub struct UnixStreamTransport {
+    stream: Mutex<Option<UnixStream>>,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixStreamTransport {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            stream: Mutex::new(None),
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStreamTransport {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let mut guard = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        if guard.is_none() {
+            let s = UnixStream::connect(&self.path)?;
+            s.set_write_timeout(Some(UDS_WRITE_TIMEOUT))?;
+            *guard = Some(s);
+        }
+        // Stream mode frames each payload with its length as a little-endian u32.
+        let mut frame = Vec::with_capacity(data.len() + 4);
+        frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        frame.extend_from_slice(data);
+        let result = guard.as_mut().unwrap().write_all(&frame);
+        if result.is_err() {
+            // Drop the connection so that the next send reconnects.
+            *guard = None;
+        }
+        result.map(|_| data.len())
+    }
+
+    fn max_packet_size(&self) -> usize {
+        DEFAULT_UDS_PACKET_SIZE
+    }
+}